@@ -1,74 +1,431 @@
 use std::any::{Any, TypeId};
 use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::dynamic::{ComponentDescriptor, DynamicComponent, DynamicQuery, RegisterError};
 use crate::entity_builder::EntityId;
+use crate::index::{ColumnIndexes, HashIndex};
+use crate::query_state::ChangeLog;
+
+/// Identifies a registered component, either a Rust type or a runtime defined one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ComponentId(pub(crate) usize);
+
+impl ComponentId {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+static NEXT_WORLD_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Tells worlds apart, component ids and entity ids only mean something in the world that assigned them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorldId(usize);
+
+impl Default for WorldId {
+    fn default() -> Self {
+        WorldId(NEXT_WORLD_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
 
 #[derive(Default)]
 pub struct Components {
     pub(crate) entities: usize,
-    //TODO use vec<usize> instead, this can cause issues after removing entities
-    pub(crate) items: HashMap<TypeId, Vec<Option<Box<dyn Any>>>>,
-    vacant: VecDeque<usize>,
+    /// One column per component, indexed by `ComponentId`
+    pub(crate) items: Vec<Column>,
+    pub(crate) info: Vec<ComponentInfo>,
+    ids: HashMap<TypeId, ComponentId>,
+    names: HashMap<String, ComponentId>,
+    indexes: HashMap<ComponentId, ColumnIndexes>,
+    pub(crate) vacant: VecDeque<usize>,
+    pub(crate) world_id: WorldId,
+    /// Entities that gained or lost components, consumed by `QueryState`
+    pub(crate) changes: ChangeLog,
 }
 
-pub struct Component<T: Any> {
-    inner: Box<dyn Any>,
-    _m: PhantomData<T>,
+pub(crate) type Column = Vec<Option<Box<dyn Any>>>;
+pub(crate) type CloneFn = fn(&dyn Any) -> Box<dyn Any>;
+pub(crate) type HashFn = fn(&dyn Any, &mut dyn Hasher);
+
+/// Static information about a registered component
+#[derive(Debug, Clone)]
+pub struct ComponentInfo {
+    pub name: String,
+    pub size: usize,
+    pub align: usize,
+    /// `None` for runtime defined components
+    pub type_id: Option<TypeId>,
+    /// Drop function of runtime defined components
+    pub(crate) drop: Option<unsafe fn(*mut u8)>,
+    /// Type erased `Clone`, only set for components registered as cloneable
+    pub(crate) clone: Option<CloneFn>,
+    /// Type erased `Hash`, only set for components registered as hashable
+    pub(crate) hash: Option<HashFn>,
+    /// Moves a value out of the pointer into the boxed representation stored in the columns
+    pub(crate) from_raw: unsafe fn(&ComponentInfo, *const u8) -> Box<dyn Any>,
+}
+
+impl ComponentInfo {
+    pub fn of<T: Any>() -> Self {
+        Self {
+            name: std::any::type_name::<T>().to_string(),
+            size: std::mem::size_of::<T>(),
+            align: std::mem::align_of::<T>(),
+            type_id: Some(TypeId::of::<T>()),
+            drop: None,
+            clone: None,
+            hash: None,
+            from_raw: box_from_raw::<T>,
+        }
+    }
+
+    pub(crate) fn dynamic(descriptor: ComponentDescriptor) -> Self {
+        Self {
+            name: descriptor.name,
+            size: descriptor.size,
+            align: descriptor.align,
+            type_id: None,
+            drop: descriptor.drop,
+            //without drop glue the value is plain bytes, copying them is a valid clone
+            clone: descriptor.drop.is_none().then_some(DynamicComponent::clone_boxed as CloneFn),
+            hash: descriptor.drop.is_none().then_some(DynamicComponent::hash_bytes as HashFn),
+            from_raw: DynamicComponent::from_raw,
+        }
+    }
+
+    pub fn is_cloneable(&self) -> bool {
+        self.clone.is_some()
+    }
+
+    pub fn is_hashable(&self) -> bool {
+        self.hash.is_some()
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.type_id.is_none()
+    }
+
+    /// Pointer to the value of a boxed component stored in a column
+    pub(crate) fn value_ptr(&self, component: &mut Box<dyn Any>) -> NonNull<u8> {
+        match self.type_id {
+            Some(_) => NonNull::from(component.as_mut()).cast(),
+            None => component.downcast_mut::<DynamicComponent>().unwrap().ptr(),
+        }
+    }
+}
+
+fn clone_component<T: Any + Clone>(component: &dyn Any) -> Box<dyn Any> {
+    Box::new(component.downcast_ref::<T>().unwrap().clone())
+}
+
+fn hash_component<T: Any + Hash>(component: &dyn Any, mut state: &mut dyn Hasher) {
+    component.downcast_ref::<T>().unwrap().hash(&mut state)
+}
+
+unsafe fn box_from_raw<T: Any>(_: &ComponentInfo, src: *const u8) -> Box<dyn Any> {
+    Box::new(std::ptr::read_unaligned(src as *const T))
 }
 
 
 impl Components {
+    pub(crate) fn register<T: Any>(&mut self) -> ComponentId {
+        let type_id = TypeId::of::<T>();
+        if let Some(id) = self.ids.get(&type_id) {
+            return *id;
+        }
+        let info = ComponentInfo::of::<T>();
+        println!("Registering {}", info.name);
+        let id = self.insert_info(info);
+        self.ids.insert(type_id, id);
+        id
+    }
+
+    pub(crate) fn register_cloneable<T: Any + Clone>(&mut self) {
+        let id = self.register::<T>();
+        self.info[id.0].clone = Some(clone_component::<T>);
+    }
+
+    pub(crate) fn register_hashable<T: Any + Hash>(&mut self) {
+        let id = self.register::<T>();
+        self.info[id.0].hash = Some(hash_component::<T>);
+    }
+
+    /// Registers a component whose layout is only known at runtime, e.g. defined by a script.
+    /// Registering the same name twice returns the existing id, as long as the layout is the same
+    pub fn register_dynamic(&mut self, descriptor: ComponentDescriptor) -> Result<ComponentId, RegisterError> {
+        if std::alloc::Layout::from_size_align(descriptor.size, descriptor.align).is_err() {
+            return Err(RegisterError::InvalidLayout { name: descriptor.name, size: descriptor.size, align: descriptor.align });
+        }
+        if let Some(id) = self.names.get(&descriptor.name) {
+            let info = &self.info[id.0];
+            if !info.is_dynamic() || info.size != descriptor.size || info.align != descriptor.align {
+                return Err(RegisterError::Conflict(descriptor.name));
+            }
+            return Ok(*id);
+        }
+        Ok(self.insert_info(ComponentInfo::dynamic(descriptor)))
+    }
+
+    fn insert_info(&mut self, info: ComponentInfo) -> ComponentId {
+        let id = ComponentId(self.info.len());
+        self.names.insert(info.name.clone(), id);
+        self.info.push(info);
+        self.items.push(vec![]);
+        id
+    }
+
+    pub fn component_id<T: Any>(&self) -> Option<ComponentId> {
+        self.ids.get(&TypeId::of::<T>()).copied()
+    }
+
+    pub fn component_id_by_name(&self, name: &str) -> Option<ComponentId> {
+        self.names.get(name).copied()
+    }
+
+    pub fn component_info(&self, id: ComponentId) -> &ComponentInfo {
+        &self.info[id.0]
+    }
+
+    /// Finds the component in this world matching one registered in another world,
+    /// Rust types are matched by `TypeId` and runtime defined components by name
+    pub(crate) fn resolve(&self, info: &ComponentInfo) -> Option<ComponentId> {
+        match info.type_id {
+            Some(type_id) => self.ids.get(&type_id).copied(),
+            None => self.names.get(&info.name)
+                .filter(|id| {
+                    let local = &self.info[id.0];
+                    local.is_dynamic() && local.size == info.size && local.align == info.align
+                })
+                .copied(),
+        }
+    }
+
+    fn id_of<T: Any>(&self) -> ComponentId {
+        self.component_id::<T>().expect("Component type not registered")
+    }
+
+    pub fn is_alive(&self, id: EntityId) -> bool {
+        id < self.entities && !self.vacant.contains(&id)
+    }
+
+    pub(crate) fn alive_mask(&self) -> Vec<bool> {
+        let mut alive = vec![true; self.entities];
+        for vacant in self.vacant.iter() {
+            alive[*vacant] = false;
+        }
+        alive
+    }
+
+    /// Number of entities currently alive
+    pub fn live_entities(&self) -> usize {
+        self.entities - self.vacant.len()
+    }
+
     pub fn new_entity(&mut self) -> EntityId {
         match self.vacant.pop_front() {
             None => { //alocate new one
                 let idx = self.entities;
-                self.items.values_mut().for_each(|components| components.push(None));
+                self.items.iter_mut().for_each(|components| components.push(None));
                 self.entities += 1;
+                self.changes.record(idx, self.entities);
                 idx
             }
-            Some(vacant) => vacant
+            Some(vacant) => {
+                self.changes.record(vacant, self.entities);
+                vacant
+            }
         }
     }
 
     pub fn remove_entity(&mut self, id: EntityId) {
-        if id >= self.entities {
-            panic!("Entity id out of bounds")
+        if !self.is_alive(id) {
+            panic!("Entity {id} is not alive")
         }
-        for (_, components) in self.items.iter_mut() {
-            components.insert(id, None)
+        for components in self.items.iter_mut() {
+            if let Some(slot) = components.get_mut(id) {
+                *slot = None;
+            }
         }
+        self.touch_all(id);
+        self.changes.record(id, self.entities);
         self.vacant.push_back(id);
-        self.entities -= 1;
     }
 
     pub fn remove_component<T: Any>(&mut self, id: EntityId) {
+        let component_id = self.id_of::<T>();
+        self.remove_component_by_id(id, component_id);
+    }
+
+    pub fn remove_component_by_id(&mut self, id: EntityId, component_id: ComponentId) {
         if id >= self.entities {
             panic!("Entity id out of bounds")
         }
-        let type_id = TypeId::of::<T>();
-        self.items.get_mut(&type_id)
-            .expect("Component not registered")
-            .insert(id, None);
+        if let Some(slot) = self.items[component_id.0].get_mut(id) {
+            *slot = None;
+        }
+        self.touch(id, component_id);
+        self.changes.record(id, self.entities);
     }
 
     pub fn add_component<T: Any>(&mut self, entity_id: EntityId, component: T) {
-        let component_vec = self.items.get_mut(&TypeId::of::<T>()).expect("Component type not registered");
-        component_vec.insert(entity_id, Some(Box::new(component)));
+        let component_id = self.id_of::<T>();
+        self.insert_boxed(entity_id, component_id, Box::new(component));
+    }
+
+    /// Adds a component by moving `size` bytes out of `src`, works for both Rust and runtime defined components.
+    ///
+    /// # Safety
+    /// `src` must point to a valid value of the component, the caller must not use or drop it afterwards
+    pub unsafe fn add_component_by_id(&mut self, entity_id: EntityId, component_id: ComponentId, src: *const u8) {
+        let info = &self.info[component_id.0];
+        let component = (info.from_raw)(info, src);
+        self.insert_boxed(entity_id, component_id, component);
+    }
+
+    pub(crate) fn insert_boxed(&mut self, entity_id: EntityId, component_id: ComponentId, component: Box<dyn Any>) {
+        let component_vec = &mut self.items[component_id.0];
+        //columns registered after entities were created can be shorter than the entity count
+        if component_vec.len() <= entity_id {
+            component_vec.resize_with(entity_id + 1, || None);
+        }
+        component_vec[entity_id] = Some(component);
+        self.touch(entity_id, component_id);
+        self.changes.record(entity_id, self.entities);
+    }
+
+    //marks the component as possibly modified so indexes over it get updated
+    fn touch(&mut self, entity_id: EntityId, component_id: ComponentId) {
+        if self.indexes.is_empty() {
+            return;
+        }
+        if let Some(indexes) = self.indexes.get_mut(&component_id) {
+            indexes.mark_dirty(entity_id, self.entities);
+        }
+    }
+
+    fn touch_all(&mut self, entity_id: EntityId) {
+        for indexes in self.indexes.values_mut() {
+            indexes.mark_dirty(entity_id, self.entities);
+        }
+    }
+
+    /// Forces every index to be rebuilt, used when columns are replaced wholesale
+    pub(crate) fn invalidate_indexes(&mut self) {
+        for indexes in self.indexes.values_mut() {
+            indexes.mark_rebuild();
+        }
+    }
+
+    pub(crate) fn add_index<C: Any, K: Hash + Eq + Clone + 'static>(&mut self, key: fn(&C) -> K) {
+        let component_id = self.register::<C>();
+        let indexes = self.indexes.entry(component_id).or_default();
+        indexes.indexes.push(Box::new(HashIndex::new(key)));
+        indexes.mark_rebuild();
+    }
+
+    /// Index registered for the component with the given key, up to date with every change made so far
+    pub fn index<C: Any, K: Hash + Eq + Clone + 'static>(&mut self) -> Option<&HashIndex<C, K>> {
+        let component_id = self.component_id::<C>()?;
+        let indexes = self.indexes.get_mut(&component_id)?;
+        indexes.refresh(&self.items[component_id.0]);
+        indexes.indexes.iter()
+            .find_map(|index| index.as_any().downcast_ref::<HashIndex<C, K>>())
+    }
+
+    /// Removes every component of the entity and frees its slot
+    pub(crate) fn take_entity(&mut self, id: EntityId) -> Vec<(ComponentId, Box<dyn Any>)> {
+        if !self.is_alive(id) {
+            panic!("Entity {id} is not alive")
+        }
+        let taken = self.items.iter_mut()
+            .enumerate()
+            .filter_map(|(idx, components)| {
+                let component = components.get_mut(id)?.take()?;
+                Some((ComponentId(idx), component))
+            })
+            .collect();
+        self.touch_all(id);
+        self.changes.record(id, self.entities);
+        self.vacant.push_back(id);
+        taken
+    }
+
+    /// Clones every component of the entity, panics if any of them was not registered as cloneable
+    pub(crate) fn clone_entity(&self, id: EntityId) -> Vec<(ComponentId, Box<dyn Any>)> {
+        if !self.is_alive(id) {
+            panic!("Entity {id} is not alive")
+        }
+        self.items.iter()
+            .enumerate()
+            .filter_map(|(idx, components)| {
+                let component = components.get(id)?.as_ref()?;
+                let info = &self.info[idx];
+                let clone = info.clone.unwrap_or_else(|| panic!("Component {} is not cloneable", info.name));
+                Some((ComponentId(idx), clone(component.as_ref())))
+            })
+            .collect()
+    }
+
+    /// Creates a new entity out of type erased components
+    pub(crate) fn spawn_boxed(&mut self, components: Vec<(ComponentId, Box<dyn Any>)>) -> EntityId {
+        let id = self.new_entity();
+        for (component_id, component) in components {
+            self.insert_boxed(id, component_id, component);
+        }
+        id
+    }
+
+    pub fn has_component(&self, entity_id: EntityId, component_id: ComponentId) -> bool {
+        matches!(self.items[component_id.0].get(entity_id), Some(Some(_)))
     }
 
     pub fn get_component<T: Any>(&mut self, entity_id: EntityId) -> Option<&mut T> {
-        let component = self.items.get_mut(&TypeId::of::<T>())
-            .unwrap()
-            .get_mut(entity_id)?;
+        let component_id = self.id_of::<T>();
+        if !self.has_component(entity_id, component_id) {
+            return None;
+        }
+        self.touch(entity_id, component_id);
+        let component = self.items[component_id.0].get_mut(entity_id)?;
         match component {
             None => None,
             Some(c) => Some(c.downcast_mut().unwrap())
         }
     }
 
+    pub fn get_component_ref<T: Any>(&self, entity_id: EntityId) -> Option<&T> {
+        let component_id = self.id_of::<T>();
+        self.items[component_id.0].get(entity_id)?
+            .as_ref()
+            .map(|c| c.downcast_ref().unwrap())
+    }
 
-    pub fn query<Tuple>(&mut self) -> Query<Tuple> {
+    /// Raw pointer to the component value, valid until the component is removed or replaced
+    pub fn get_component_ptr(&mut self, entity_id: EntityId, component_id: ComponentId) -> Option<NonNull<u8>> {
+        if !self.has_component(entity_id, component_id) {
+            return None;
+        }
+        self.touch(entity_id, component_id);
+        let info = &self.info[component_id.0];
+        let component = self.items[component_id.0].get_mut(entity_id)?.as_mut()?;
+        Some(info.value_ptr(component))
+    }
+
+    /// Iterates over every entity that has all the given components, yielding raw pointers in the same order as `ids`
+    pub fn query_dynamic(&mut self, ids: &[ComponentId]) -> DynamicQuery<'_> {
+        DynamicQuery::new(self, ids.to_vec())
+    }
+
+    /// Panics if the same component appears more than once in `Tuple`,
+    /// that would hand out two mutable references to the same value
+    pub fn query<Tuple>(&mut self) -> Query<'_, Tuple>
+        where
+            Tuple: for<'b> Fetch<'b>
+    {
+        check_unique::<Tuple>();
         Query {
             entity_idx: 0,
             components: self,
@@ -77,6 +434,18 @@ impl Components {
     }
 }
 
+pub(crate) fn check_unique<Tuple>()
+    where
+        Tuple: for<'b> Fetch<'b>
+{
+    let types = Tuple::type_info();
+    for (i, (type_id, name)) in types.iter().enumerate() {
+        if types[..i].iter().any(|(other, _)| other == type_id) {
+            panic!("Component {name} requested more than once in the same query")
+        }
+    }
+}
+
 pub trait LendingIterator {
     type Item<'a> where Self: 'a;
     fn next(&mut self) -> Option<Self::Item<'_>>;
@@ -107,13 +476,132 @@ impl<'iter, Tuple> LendingIterator for Query<'iter, Tuple>
     fn next(&mut self) -> Option<Self::Item<'_>> {
         let entities = self.components.entities;
         while self.entity_idx < entities {
-            if let Some(comp) = Tuple::fetch(&mut self.components, self.entity_idx) {
+            if let Some(comp) = Tuple::fetch(self.components, self.entity_idx) {
                 self.entity_idx += 1;
                 return Some(comp);
             }
             self.entity_idx += 1;
         }
-        return None;
+        None
+    }
+}
+
+/// Std iterator over a query, for `for` loops and iterator adapters
+pub struct QueryIter<'a, Tuple>(Query<'a, Tuple>);
+
+/// Each entity is visited once and the components of a query are distinct types,
+/// so the mutable references handed out never alias and can outlive the call to `next`
+impl<'a, Tuple> Iterator for QueryIter<'a, Tuple>
+    where
+        Tuple: Fetch<'a>
+{
+    type Item = <Tuple as Fetch<'a>>::Data;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let query = &mut self.0;
+        let entities = query.components.entities;
+        while query.entity_idx < entities {
+            let entity_id = query.entity_idx;
+            query.entity_idx += 1;
+            if let Some(comp) = Tuple::fetch(query.components, entity_id) {
+                return Some(comp);
+            }
+        }
+        None
+    }
+}
+
+impl<'a, Tuple> IntoIterator for Query<'a, Tuple>
+    where
+        Tuple: Fetch<'a>
+{
+    type Item = <Tuple as Fetch<'a>>::Data;
+    type IntoIter = QueryIter<'a, Tuple>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        QueryIter(self)
+    }
+}
+
+impl<'a, Tuple> Query<'a, Tuple>
+    where
+        Tuple: for<'b> Fetch<'b>
+{
+    /// Iterates the matching entities ordered by a key taken from component `C`,
+    /// entities without `C` are skipped
+    pub fn sorted_by_key<C, K, F>(self, key: F) -> SortedQuery<'a, Tuple>
+        where
+            C: Any,
+            K: Ord,
+            F: Fn(&C) -> K,
+    {
+        let components = self.components;
+        let mut keyed: Vec<(K, EntityId)> = (self.entity_idx..components.entities)
+            .filter(|entity_id| Tuple::matches(components, *entity_id))
+            .filter_map(|entity_id| Some((key(components.get_component_ref::<C>(entity_id)?), entity_id)))
+            .collect();
+        //stable so entities with the same key keep their id order
+        keyed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        SortedQuery {
+            entity_ids: keyed.into_iter().map(|(_, id)| id).collect::<Vec<_>>().into_iter(),
+            components,
+            _m: PhantomData,
+        }
+    }
+}
+
+pub struct SortedQuery<'a, Tuple> {
+    entity_ids: std::vec::IntoIter<EntityId>,
+    components: &'a mut Components,
+    _m: PhantomData<Tuple>,
+}
+
+impl<'iter, Tuple> LendingIterator for SortedQuery<'iter, Tuple>
+    where
+        Tuple: for<'b> Fetch<'b>
+{
+    type Item<'a> = <Tuple as Fetch<'a>>::Data where Self: 'a;
+
+    fn next(&mut self) -> Option<Self::Item<'_>> {
+        for entity_id in self.entity_ids.by_ref() {
+            if let Some(comp) = Tuple::fetch(self.components, entity_id) {
+                return Some(comp);
+            }
+        }
+        None
+    }
+}
+
+/// Std iterator over a sorted query, see [`QueryIter`]
+pub struct SortedQueryIter<'a, Tuple>(SortedQuery<'a, Tuple>);
+
+impl<'a, Tuple> Iterator for SortedQueryIter<'a, Tuple>
+    where
+        Tuple: Fetch<'a>
+{
+    type Item = <Tuple as Fetch<'a>>::Data;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let query = &mut self.0;
+        for entity_id in query.entity_ids.by_ref() {
+            if let Some(comp) = Tuple::fetch(query.components, entity_id) {
+                return Some(comp);
+            }
+        }
+        None
+    }
+}
+
+impl<'a, Tuple> IntoIterator for SortedQuery<'a, Tuple>
+    where
+        Tuple: Fetch<'a>
+{
+    type Item = <Tuple as Fetch<'a>>::Data;
+    type IntoIter = SortedQueryIter<'a, Tuple>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        SortedQueryIter(self)
     }
 }
 
@@ -121,6 +609,8 @@ impl<'iter, Tuple> LendingIterator for Query<'iter, Tuple>
 pub trait Fetch<'a> {
     type Data;
     fn fetch(components: &mut Components, entity_id: EntityId) -> Option<Self::Data>;
+    /// Whether the entity has every component, without accessing them
+    fn matches(components: &Components, entity_id: EntityId) -> bool;
     fn type_info() -> Vec<(TypeId, &'static str)>;
 }
 
@@ -136,6 +626,7 @@ macro_rules! fetch_tuple {
          {
             type Data = ($(&'a mut $ty,)*);
 
+            #[allow(unused_variables, unused_unsafe)]
             fn fetch(components: &mut Components, entity_id: usize) -> Option<Self::Data> {
                unsafe {
                     Some((
@@ -144,6 +635,11 @@ macro_rules! fetch_tuple {
                }
             }
              
+             #[allow(unused_variables)]
+             fn matches(components: &Components, entity_id: usize) -> bool {
+                 $(components.get_component_ref::<$ty>(entity_id).is_some() &&)* true
+             }
+
              fn type_info() -> Vec<(TypeId, &'static str)> {
                 vec![
                     $((TypeId::of::<$ty>(), std::any::type_name::<$ty>()),)*
@@ -0,0 +1,147 @@
+use std::any::Any;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::component::Components;
+
+/// Point in time view of the entities and component storage of a world
+#[derive(Debug, Clone)]
+pub struct WorldDiagnostics {
+    pub live_entities: usize,
+    pub vacant_entities: usize,
+    pub components: Vec<ComponentDiagnostics>,
+}
+
+/// Memory used by a single component column
+#[derive(Debug, Clone)]
+pub struct ComponentDiagnostics {
+    pub name: String,
+    /// Number of entities that have this component
+    pub count: usize,
+    /// Slots allocated for the column, including empty ones
+    pub slots: usize,
+    /// Approximate heap usage: the slot vector plus one boxed component per entity
+    pub bytes: usize,
+}
+
+impl WorldDiagnostics {
+    pub(crate) fn collect(components: &Components) -> Self {
+        let mut columns: Vec<ComponentDiagnostics> = components.items.iter()
+            .zip(components.info.iter())
+            .map(|(column, info)| {
+                let count = column.iter().filter(|c| c.is_some()).count();
+                let slot_size = std::mem::size_of::<Option<Box<dyn Any>>>();
+                ComponentDiagnostics {
+                    name: info.name.clone(),
+                    count,
+                    slots: column.len(),
+                    bytes: column.capacity() * slot_size + count * info.size,
+                }
+            })
+            .collect();
+        columns.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self {
+            live_entities: components.live_entities(),
+            vacant_entities: components.vacant.len(),
+            components: columns,
+        }
+    }
+
+    /// Total bytes used by all component columns
+    pub fn total_bytes(&self) -> usize {
+        self.components.iter().map(|c| c.bytes).sum()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SystemTiming {
+    pub name: String,
+    /// Offset from when the recorder was created
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// Records how long each system run took
+#[derive(Debug)]
+pub struct SystemTimings {
+    created: Instant,
+    records: Vec<SystemTiming>,
+}
+
+impl Default for SystemTimings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SystemTimings {
+    pub fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            records: vec![],
+        }
+    }
+
+    pub fn record(&mut self, name: &str, start: Instant, duration: Duration) {
+        self.records.push(SystemTiming {
+            name: name.to_string(),
+            start: start.saturating_duration_since(self.created),
+            duration,
+        });
+    }
+
+    pub fn records(&self) -> &[SystemTiming] {
+        &self.records
+    }
+
+    /// Sum of all recorded runs of the system with the given name
+    pub fn total(&self, name: &str) -> Duration {
+        self.records.iter()
+            .filter(|r| r.name == name)
+            .map(|r| r.duration)
+            .sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Serializes the recorded timings using the Chrome trace event format,
+    /// the output can be loaded in chrome://tracing or https://ui.perfetto.dev
+    pub fn to_chrome_trace(&self) -> String {
+        let mut out = String::from("{\"traceEvents\":[");
+        for (i, record) in self.records.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(
+                out,
+                "{{\"name\":\"{}\",\"cat\":\"system\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\"tid\":1}}",
+                escape_json(&record.name),
+                record.start.as_micros(),
+                record.duration.as_micros()
+            ).unwrap();
+        }
+        out.push_str("],\"displayTimeUnit\":\"ms\"}");
+        out
+    }
+
+    pub fn write_chrome_trace<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, self.to_chrome_trace())
+    }
+}
+
+fn escape_json(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,147 @@
+use std::alloc::Layout;
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::hash::Hasher;
+use std::{error, fmt};
+use std::ptr::NonNull;
+
+use crate::component::{ComponentId, ComponentInfo, Components};
+use crate::entity_builder::EntityId;
+
+/// Layout of a component defined at runtime, e.g. by a Lua script or a JSON file
+#[derive(Debug, Clone)]
+pub struct ComponentDescriptor {
+    pub name: String,
+    pub size: usize,
+    pub align: usize,
+    /// Called with a pointer to the value before its memory is released.
+    /// Components without a drop function are treated as plain bytes and can be cloned and hashed
+    pub drop: Option<unsafe fn(*mut u8)>,
+}
+
+impl ComponentDescriptor {
+    pub fn new(name: impl Into<String>, size: usize, align: usize) -> Self {
+        Self {
+            name: name.into(),
+            size,
+            align,
+            drop: None,
+        }
+    }
+
+    pub fn with_drop(mut self, drop: unsafe fn(*mut u8)) -> Self {
+        self.drop = Some(drop);
+        self
+    }
+}
+
+/// Why a runtime defined component couldn't be registered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+    /// The size and align don't make a valid `Layout`, e.g. the align is not a power of two
+    InvalidLayout { name: String, size: usize, align: usize },
+    /// The name is already taken by a Rust type or a component with a different layout
+    Conflict(String),
+}
+
+impl error::Error for RegisterError {}
+
+impl Display for RegisterError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            RegisterError::InvalidLayout { ref name, size, align } => write!(f, "Invalid layout for component {} [size {}, align {}]", name, size, align),
+            RegisterError::Conflict(ref name) => write!(f, "Component already registered with a different layout [{}]", name),
+        }
+    }
+}
+
+/// Heap allocated value of a runtime defined component, stored in the columns like any other boxed component
+pub(crate) struct DynamicComponent {
+    ptr: NonNull<u8>,
+    layout: Layout,
+    drop: Option<unsafe fn(*mut u8)>,
+}
+
+impl DynamicComponent {
+    fn alloc(layout: Layout) -> NonNull<u8> {
+        if layout.size() == 0 {
+            return NonNull::new(std::ptr::without_provenance_mut(layout.align())).unwrap();
+        }
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        NonNull::new(ptr).unwrap_or_else(|| std::alloc::handle_alloc_error(layout))
+    }
+
+    pub(crate) unsafe fn from_raw(info: &ComponentInfo, src: *const u8) -> Box<dyn Any> {
+        let layout = Layout::from_size_align(info.size, info.align).expect("Layout checked on registration");
+        let ptr = Self::alloc(layout);
+        std::ptr::copy_nonoverlapping(src, ptr.as_ptr(), layout.size());
+        Box::new(DynamicComponent { ptr, layout, drop: info.drop })
+    }
+
+    pub(crate) fn clone_boxed(component: &dyn Any) -> Box<dyn Any> {
+        let component = component.downcast_ref::<DynamicComponent>().unwrap();
+        let ptr = Self::alloc(component.layout);
+        unsafe { std::ptr::copy_nonoverlapping(component.ptr.as_ptr(), ptr.as_ptr(), component.layout.size()) };
+        Box::new(DynamicComponent { ptr, layout: component.layout, drop: None })
+    }
+
+    pub(crate) fn hash_bytes(component: &dyn Any, state: &mut dyn Hasher) {
+        let component = component.downcast_ref::<DynamicComponent>().unwrap();
+        let bytes = unsafe { std::slice::from_raw_parts(component.ptr.as_ptr(), component.layout.size()) };
+        state.write(bytes);
+    }
+
+    pub(crate) fn ptr(&self) -> NonNull<u8> {
+        self.ptr
+    }
+}
+
+impl Drop for DynamicComponent {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(drop) = self.drop {
+                drop(self.ptr.as_ptr());
+            }
+            if self.layout.size() > 0 {
+                std::alloc::dealloc(self.ptr.as_ptr(), self.layout);
+            }
+        }
+    }
+}
+
+/// Query built from a list of component ids, yields the entity id and a pointer to each requested component.
+/// Dereferencing the pointers is up to the caller, they stay valid while the query borrows the components
+pub struct DynamicQuery<'a> {
+    entity_idx: usize,
+    ids: Vec<ComponentId>,
+    components: &'a mut Components,
+}
+
+impl<'a> DynamicQuery<'a> {
+    pub(crate) fn new(components: &'a mut Components, ids: Vec<ComponentId>) -> Self {
+        Self {
+            entity_idx: 0,
+            ids,
+            components,
+        }
+    }
+}
+
+impl Iterator for DynamicQuery<'_> {
+    type Item = (EntityId, Vec<NonNull<u8>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.entity_idx < self.components.entities {
+            let entity_id = self.entity_idx;
+            self.entity_idx += 1;
+
+            let found: Option<Vec<_>> = self.ids.iter()
+                .map(|id| self.components.get_component_ptr(entity_id, *id))
+                .collect();
+            if let Some(ptrs) = found {
+                return Some((entity_id, ptrs));
+            }
+        }
+        None
+    }
+}
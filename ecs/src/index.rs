@@ -0,0 +1,129 @@
+use std::any::Any;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+
+use crate::entity_builder::EntityId;
+
+/// Secondary index over the values of a component column
+pub(crate) trait ComponentIndex {
+    /// Called with the current value of the component, `None` if the entity no longer has it
+    fn update(&mut self, entity_id: EntityId, component: Option<&dyn Any>);
+    fn clear(&mut self);
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Index mapping a key derived from a component to the entities holding it.
+/// When several entities share a key, `get` returns the one with the lowest id
+pub struct HashIndex<C, K> {
+    key: fn(&C) -> K,
+    by_key: HashMap<K, BTreeSet<EntityId>>,
+    by_entity: HashMap<EntityId, K>,
+}
+
+impl<C: Any, K: Hash + Eq + Clone + 'static> HashIndex<C, K> {
+    pub(crate) fn new(key: fn(&C) -> K) -> Self {
+        Self {
+            key,
+            by_key: HashMap::new(),
+            by_entity: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<EntityId> {
+        self.by_key.get(key).and_then(|entities| entities.first()).copied()
+    }
+
+    /// Every entity holding the key, in id order
+    pub fn get_all(&self, key: &K) -> impl Iterator<Item = EntityId> + '_ {
+        self.by_key.get(key).into_iter().flatten().copied()
+    }
+
+    /// Number of distinct keys
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+}
+
+impl<C: Any, K: Hash + Eq + Clone + 'static> ComponentIndex for HashIndex<C, K> {
+    fn update(&mut self, entity_id: EntityId, component: Option<&dyn Any>) {
+        if let Some(old) = self.by_entity.remove(&entity_id) {
+            if let Some(entities) = self.by_key.get_mut(&old) {
+                entities.remove(&entity_id);
+                if entities.is_empty() {
+                    self.by_key.remove(&old);
+                }
+            }
+        }
+        if let Some(component) = component {
+            let key = (self.key)(component.downcast_ref::<C>().unwrap());
+            self.by_key.entry(key.clone()).or_default().insert(entity_id);
+            self.by_entity.insert(entity_id, key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.by_key.clear();
+        self.by_entity.clear();
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Indexes registered for a single component column.
+/// Any mutable access to the component marks the entity as dirty, indexes are brought up to date on the next lookup
+#[derive(Default)]
+pub(crate) struct ColumnIndexes {
+    pub(crate) indexes: Vec<Box<dyn ComponentIndex>>,
+    dirty: Vec<EntityId>,
+    rebuild: bool,
+}
+
+impl ColumnIndexes {
+    pub(crate) fn mark_dirty(&mut self, entity_id: EntityId, entities: usize) {
+        if self.rebuild {
+            return;
+        }
+        //touched more entities than there are, cheaper to rebuild than to keep growing the list
+        if self.dirty.len() >= entities.max(64) {
+            self.mark_rebuild();
+            return;
+        }
+        self.dirty.push(entity_id);
+    }
+
+    pub(crate) fn mark_rebuild(&mut self) {
+        self.dirty.clear();
+        self.rebuild = true;
+    }
+
+    pub(crate) fn refresh(&mut self, column: &[Option<Box<dyn Any>>]) {
+        if self.rebuild {
+            self.rebuild = false;
+            self.dirty.clear();
+            for index in self.indexes.iter_mut() {
+                index.clear();
+                for (entity_id, component) in column.iter().enumerate() {
+                    if let Some(component) = component {
+                        index.update(entity_id, Some(component.as_ref()));
+                    }
+                }
+            }
+            return;
+        }
+
+        self.dirty.sort_unstable();
+        self.dirty.dedup();
+        for entity_id in self.dirty.drain(..) {
+            let component = column.get(entity_id).and_then(|c| c.as_deref());
+            for index in self.indexes.iter_mut() {
+                index.update(entity_id, component);
+            }
+        }
+    }
+}
@@ -1,6 +1,10 @@
-#![feature(generic_associated_types)]
-
 pub mod component;
+pub mod diagnostics;
+pub mod dynamic;
 pub mod entity_builder;
+pub mod index;
+pub mod prefab;
+pub mod query_state;
 pub mod resource;
+pub mod snapshot;
 pub mod world;
\ No newline at end of file
@@ -0,0 +1,247 @@
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::{error, fmt, io};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::component::{ComponentId, Components};
+use crate::entity_builder::EntityId;
+
+/// Added to every child entity spawned from a prefab, pointing to the entity it was spawned under
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parent(pub EntityId);
+
+#[derive(Debug)]
+pub enum PrefabError {
+    IO(io::Error),
+    Parse(String),
+    UnknownFormat(String),
+    UnknownComponent(String),
+    InvalidComponent { name: String, message: String },
+}
+
+impl error::Error for PrefabError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PrefabError::IO(ref io) => Some(io),
+            _ => None,
+        }
+    }
+}
+
+impl Display for PrefabError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            PrefabError::IO(ref io) => write!(f, "IO Error [{}]", io),
+            PrefabError::Parse(ref msg) => write!(f, "Invalid prefab [{}]", msg),
+            PrefabError::UnknownFormat(ref ext) => write!(f, "Unknown prefab format [{}]", ext),
+            PrefabError::UnknownComponent(ref name) => write!(f, "Component not registered for prefabs [{}]", name),
+            PrefabError::InvalidComponent { ref name, ref message } => write!(f, "Invalid value for component {} [{}]", name, message),
+        }
+    }
+}
+
+impl From<io::Error> for PrefabError {
+    fn from(err: io::Error) -> Self {
+        PrefabError::IO(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrefabFormat {
+    Ron,
+    Json,
+}
+
+/// Serialized component value, only deserialized when the prefab is spawned
+/// since the concrete type is known by the world it is spawned into
+#[derive(Debug, Clone)]
+pub struct PrefabValue {
+    format: PrefabFormat,
+    raw: String,
+}
+
+impl PrefabValue {
+    pub fn ron(raw: impl Into<String>) -> Self {
+        Self { format: PrefabFormat::Ron, raw: raw.into() }
+    }
+
+    pub fn json(raw: impl Into<String>) -> Self {
+        Self { format: PrefabFormat::Json, raw: raw.into() }
+    }
+}
+
+/// Template for an entity and its children, keyed by the names components were registered with.
+///
+/// ```ron
+/// (
+///     components: {
+///         "Health": Health(100),
+///         "Speed": Speed(3),
+///     },
+///     children: [
+///         (components: { "Health": Health(10) }),
+///     ],
+/// )
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Prefab {
+    pub components: BTreeMap<String, PrefabValue>,
+    pub children: Vec<Prefab>,
+}
+
+#[derive(Deserialize)]
+struct RawPrefab<V> {
+    #[serde(default = "BTreeMap::new")]
+    components: BTreeMap<String, V>,
+    #[serde(default = "Vec::new")]
+    children: Vec<RawPrefab<V>>,
+}
+
+impl<V> RawPrefab<V> {
+    fn into_prefab(self, to_value: &impl Fn(V) -> PrefabValue) -> Prefab {
+        Prefab {
+            components: self.components.into_iter().map(|(name, v)| (name, to_value(v))).collect(),
+            children: self.children.into_iter().map(|c| c.into_prefab(to_value)).collect(),
+        }
+    }
+}
+
+impl Prefab {
+    pub fn from_ron(source: &str) -> Result<Self, PrefabError> {
+        let raw: RawPrefab<Box<ron::value::RawValue>> = ron::from_str(source)
+            .map_err(|e| PrefabError::Parse(e.to_string()))?;
+        Ok(raw.into_prefab(&|v| PrefabValue::ron(v.get_ron())))
+    }
+
+    pub fn from_json(source: &str) -> Result<Self, PrefabError> {
+        let raw: RawPrefab<Box<serde_json::value::RawValue>> = serde_json::from_str(source)
+            .map_err(|e| PrefabError::Parse(e.to_string()))?;
+        Ok(raw.into_prefab(&|v| PrefabValue::json(v.get())))
+    }
+
+    /// Loads a prefab from a `.ron` or `.json` file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PrefabError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("ron") => Self::from_ron(&source),
+            Some("json") => Self::from_json(&source),
+            other => Err(PrefabError::UnknownFormat(other.unwrap_or_default().to_string())),
+        }
+    }
+}
+
+/// Per instance values replacing the ones defined in the prefab root
+#[derive(Default)]
+pub struct PrefabOverrides {
+    values: BTreeMap<String, PrefabValue>,
+    components: Vec<(TypeId, &'static str, Box<dyn Any>)>,
+}
+
+impl PrefabOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<T: Any>(mut self, component: T) -> Self {
+        self.components.push((TypeId::of::<T>(), std::any::type_name::<T>(), Box::new(component)));
+        self
+    }
+
+    pub fn with_value(mut self, name: impl Into<String>, value: PrefabValue) -> Self {
+        self.values.insert(name.into(), value);
+        self
+    }
+}
+
+type DeserializeFn = fn(&str) -> Result<Box<dyn Any>, String>;
+
+struct PrefabComponent {
+    id: ComponentId,
+    ron: DeserializeFn,
+    json: DeserializeFn,
+}
+
+/// Maps the component names used in prefab files to registered component types
+#[derive(Default)]
+pub struct PrefabRegistry {
+    components: HashMap<String, PrefabComponent>,
+    by_type: HashMap<TypeId, ComponentId>,
+}
+
+fn from_ron<T: DeserializeOwned + Any>(raw: &str) -> Result<Box<dyn Any>, String> {
+    ron::from_str::<T>(raw).map(|v| Box::new(v) as Box<dyn Any>).map_err(|e| e.to_string())
+}
+
+fn from_json<T: DeserializeOwned + Any>(raw: &str) -> Result<Box<dyn Any>, String> {
+    serde_json::from_str::<T>(raw).map(|v| Box::new(v) as Box<dyn Any>).map_err(|e| e.to_string())
+}
+
+//entity tree with every value already deserialized, built before touching the world
+struct Resolved {
+    components: Vec<(ComponentId, Box<dyn Any>)>,
+    children: Vec<Resolved>,
+}
+
+impl PrefabRegistry {
+    pub(crate) fn register<T: DeserializeOwned + Any>(&mut self, name: &str, id: ComponentId) {
+        self.components.insert(name.to_string(), PrefabComponent {
+            id,
+            ron: from_ron::<T>,
+            json: from_json::<T>,
+        });
+        self.by_type.insert(TypeId::of::<T>(), id);
+    }
+
+    pub(crate) fn spawn(&self, components: &mut Components, prefab: &Prefab, overrides: PrefabOverrides) -> Result<EntityId, PrefabError> {
+        let mut values = prefab.components.clone();
+        values.extend(overrides.values);
+        let mut root = self.resolve(&values, &prefab.children)?;
+        for (type_id, name, component) in overrides.components {
+            let id = *self.by_type.get(&type_id)
+                .ok_or_else(|| PrefabError::UnknownComponent(name.to_string()))?;
+            root.components.retain(|(existing, _)| *existing != id);
+            root.components.push((id, component));
+        }
+
+        //only registered along with the prefab components
+        let parent_id = components.component_id::<Parent>()
+            .ok_or_else(|| PrefabError::UnknownComponent("Parent".to_string()))?;
+        Ok(Self::spawn_resolved(components, root, None, parent_id))
+    }
+
+    fn resolve(&self, values: &BTreeMap<String, PrefabValue>, children: &[Prefab]) -> Result<Resolved, PrefabError> {
+        let components = values.iter()
+            .map(|(name, value)| {
+                let component = self.components.get(name)
+                    .ok_or_else(|| PrefabError::UnknownComponent(name.clone()))?;
+                let deserialize = match value.format {
+                    PrefabFormat::Ron => component.ron,
+                    PrefabFormat::Json => component.json,
+                };
+                let boxed = deserialize(&value.raw)
+                    .map_err(|message| PrefabError::InvalidComponent { name: name.clone(), message })?;
+                Ok((component.id, boxed))
+            })
+            .collect::<Result<_, PrefabError>>()?;
+        let children = children.iter()
+            .map(|child| self.resolve(&child.components, &child.children))
+            .collect::<Result<_, PrefabError>>()?;
+        Ok(Resolved { components, children })
+    }
+
+    fn spawn_resolved(components: &mut Components, resolved: Resolved, parent: Option<EntityId>, parent_id: ComponentId) -> EntityId {
+        let id = components.spawn_boxed(resolved.components);
+        if let Some(parent) = parent {
+            components.insert_boxed(id, parent_id, Box::new(Parent(parent)));
+        }
+        for child in resolved.children {
+            Self::spawn_resolved(components, child, Some(id), parent_id);
+        }
+        id
+    }
+}
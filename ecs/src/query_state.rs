@@ -0,0 +1,192 @@
+use std::any::Any;
+use std::collections::btree_set;
+use std::collections::BTreeSet;
+use std::marker::PhantomData;
+
+use crate::component::{check_unique, Components, Fetch, WorldId};
+use crate::entity_builder::EntityId;
+use crate::world::World;
+
+/// Entities that gained or lost components since the last time the log was trimmed.
+/// Query states keep a cursor into it, when it grows too large it is cleared and the epoch
+/// bumped, forcing every query state to rebuild from scratch
+#[derive(Default)]
+pub(crate) struct ChangeLog {
+    epoch: u64,
+    entities: Vec<EntityId>,
+}
+
+impl ChangeLog {
+    const MIN_CAPACITY: usize = 1024;
+
+    pub(crate) fn record(&mut self, entity_id: EntityId, entities: usize) {
+        if self.entities.len() >= (entities * 2).max(Self::MIN_CAPACITY) {
+            self.reset();
+        }
+        self.entities.push(entity_id);
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.entities.clear();
+        self.epoch += 1;
+    }
+}
+
+/// Extra conditions on the entities matched by a `QueryState`, on top of having every fetched component
+pub trait QueryFilter {
+    fn matches(components: &Components, entity_id: EntityId) -> bool;
+}
+
+/// Only entities that have component `T`
+pub struct With<T>(PhantomData<T>);
+
+/// Only entities that don't have component `T`
+pub struct Without<T>(PhantomData<T>);
+
+impl QueryFilter for () {
+    fn matches(_: &Components, _: EntityId) -> bool {
+        true
+    }
+}
+
+impl<T: Any> QueryFilter for With<T> {
+    fn matches(components: &Components, entity_id: EntityId) -> bool {
+        components.get_component_ref::<T>(entity_id).is_some()
+    }
+}
+
+impl<T: Any> QueryFilter for Without<T> {
+    fn matches(components: &Components, entity_id: EntityId) -> bool {
+        components.get_component_ref::<T>(entity_id).is_none()
+    }
+}
+
+macro_rules! filter_tuple {
+    ($($ty: ident),*) => {
+        impl<$($ty: QueryFilter,)*> QueryFilter for ($($ty,)*) {
+            fn matches(components: &Components, entity_id: EntityId) -> bool {
+                $($ty::matches(components, entity_id) &&)* true
+            }
+        }
+    }
+}
+
+filter_tuple! {F0}
+filter_tuple! {F0, F1}
+filter_tuple! {F0, F1, F2}
+filter_tuple! {F0, F1, F2, F3}
+
+/// Query that remembers which entities matched, so running it every frame only costs the iteration.
+/// Only entities whose components changed since the last run are checked again
+///
+/// ```ignore
+/// let mut moving = QueryState::<(Position, Speed), Without<Frozen>>::new();
+/// for (position, speed) in moving.iter(&mut world) { .. }
+/// ```
+pub struct QueryState<Q, F = ()> {
+    world_id: Option<WorldId>,
+    epoch: u64,
+    cursor: usize,
+    matched: BTreeSet<EntityId>,
+    _m: PhantomData<fn() -> (Q, F)>,
+}
+
+impl<Q, F> Default for QueryState<Q, F>
+    where
+        Q: for<'a> Fetch<'a>,
+        F: QueryFilter
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Q, F> QueryState<Q, F>
+    where
+        Q: for<'a> Fetch<'a>,
+        F: QueryFilter
+{
+    pub fn new() -> Self {
+        Self {
+            world_id: None,
+            epoch: 0,
+            cursor: 0,
+            matched: BTreeSet::new(),
+            _m: PhantomData,
+        }
+    }
+
+    /// Brings the matched entities up to date with the changes made to the world
+    pub fn update(&mut self, components: &Components) {
+        let changes = &components.changes;
+        if self.world_id != Some(components.world_id) || self.epoch != changes.epoch {
+            self.world_id = Some(components.world_id);
+            self.epoch = changes.epoch;
+            self.cursor = changes.entities.len();
+            self.matched = components.alive_mask().into_iter()
+                .enumerate()
+                .filter(|(entity_id, alive)| *alive && Self::matches(components, *entity_id))
+                .map(|(entity_id, _)| entity_id)
+                .collect();
+            return;
+        }
+
+        for entity_id in &changes.entities[self.cursor..] {
+            if components.is_alive(*entity_id) && Self::matches(components, *entity_id) {
+                self.matched.insert(*entity_id);
+            } else {
+                self.matched.remove(entity_id);
+            }
+        }
+        self.cursor = changes.entities.len();
+    }
+
+    fn matches(components: &Components, entity_id: EntityId) -> bool {
+        Q::matches(components, entity_id) && F::matches(components, entity_id)
+    }
+
+    pub fn iter<'a>(&'a mut self, world: &'a mut World) -> QueryStateIter<'a, Q> {
+        self.iter_components(&mut world.components)
+    }
+
+    pub fn iter_components<'a>(&'a mut self, components: &'a mut Components) -> QueryStateIter<'a, Q> {
+        check_unique::<Q>();
+        self.update(components);
+        QueryStateIter {
+            entity_ids: self.matched.iter(),
+            components,
+            _m: PhantomData,
+        }
+    }
+
+    /// Number of matching entities as of the last update
+    pub fn len(&self) -> usize {
+        self.matched.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matched.is_empty()
+    }
+}
+
+pub struct QueryStateIter<'a, Q> {
+    entity_ids: btree_set::Iter<'a, EntityId>,
+    components: &'a mut Components,
+    _m: PhantomData<Q>,
+}
+
+impl<'a, Q> Iterator for QueryStateIter<'a, Q>
+    where
+        Q: Fetch<'a>
+{
+    type Item = <Q as Fetch<'a>>::Data;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for entity_id in self.entity_ids.by_ref() {
+            if let Some(comp) = Q::fetch(self.components, *entity_id) {
+                return Some(comp);
+            }
+        }
+        None
+    }
+}
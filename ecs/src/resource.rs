@@ -1,12 +1,52 @@
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
 
+use crate::component::CloneFn;
+
 #[derive(Default)]
 pub struct Resources {
     items: HashMap<TypeId, Box<dyn Any>>,
+    /// Resources included in snapshots
+    cloneable: HashMap<TypeId, CloneFn>,
+}
+
+/// Copy of the cloneable resources taken by [`Resources::snapshot`]
+pub struct ResourcesSnapshot {
+    items: HashMap<TypeId, Box<dyn Any>>,
+}
+
+fn clone_resource<T: Any + Clone>(resource: &dyn Any) -> Box<dyn Any> {
+    Box::new(resource.downcast_ref::<T>().unwrap().clone())
 }
 
 impl Resources {
+    /// Adds a resource that is captured by snapshots and put back on restore
+    pub fn add_cloneable_resource<T: Any + Clone>(&mut self, resource: T) -> &mut Self {
+        self.cloneable.insert(TypeId::of::<T>(), clone_resource::<T>);
+        self.add_resource(resource)
+    }
+
+    pub fn snapshot(&self) -> ResourcesSnapshot {
+        let items = self.cloneable.iter()
+            .filter_map(|(type_id, clone)| {
+                let resource = self.items.get(type_id)?;
+                Some((*type_id, clone(resource.as_ref())))
+            })
+            .collect();
+        ResourcesSnapshot { items }
+    }
+
+    /// Puts back every cloneable resource as it was when the snapshot was taken,
+    /// the ones that didn't exist at the time are removed
+    pub fn restore(&mut self, snapshot: &ResourcesSnapshot) {
+        for (type_id, clone) in self.cloneable.iter() {
+            match snapshot.items.get(type_id) {
+                Some(resource) => self.items.insert(*type_id, clone(resource.as_ref())),
+                None => self.items.remove(type_id),
+            };
+        }
+    }
+
     pub fn add_resource<T: Any>(&mut self, resource: T) -> &mut Self {
         self.items.insert(TypeId::of::<T>(), Box::new(resource));
         self
@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::hash::Hasher;
+use std::{error, fmt};
+
+use crate::component::{Column, Components, WorldId};
+use crate::resource::ResourcesSnapshot;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// Component ids only mean something in the world that assigned them
+    DifferentWorld,
+}
+
+impl error::Error for SnapshotError {}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match *self {
+            SnapshotError::DifferentWorld => write!(f, "Snapshot was taken from a different world"),
+        }
+    }
+}
+
+/// FNV-1a, integers are always written as little endian so the same state produces
+/// the same hash on every platform and Rust version, unlike `DefaultHasher`
+pub struct StableHasher {
+    state: u64,
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StableHasher {
+    const OFFSET: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    pub fn new() -> Self {
+        Self { state: Self::OFFSET }
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.state
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write(&[i])
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes())
+    }
+
+    //usize differs between 32 and 64 bit peers
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_i8(&mut self, i: i8) {
+        self.write_u8(i as u8)
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16)
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32)
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128)
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as u64)
+    }
+}
+
+/// Copy of the cloneable component columns, components not registered as cloneable are left untouched on restore
+pub struct ComponentsSnapshot {
+    world_id: WorldId,
+    entities: usize,
+    vacant: VecDeque<usize>,
+    columns: Vec<Option<Column>>,
+}
+
+impl ComponentsSnapshot {
+    pub(crate) fn take(components: &Components) -> Self {
+        let columns = components.items.iter()
+            .zip(components.info.iter())
+            .map(|(column, info)| {
+                let clone = info.clone?;
+                Some(column.iter()
+                    .map(|slot| slot.as_ref().map(|c| clone(c.as_ref())))
+                    .collect())
+            })
+            .collect();
+
+        Self {
+            world_id: components.world_id,
+            entities: components.entities,
+            vacant: components.vacant.clone(),
+            columns,
+        }
+    }
+
+    pub(crate) fn restore(&self, components: &mut Components) -> Result<(), SnapshotError> {
+        if self.world_id != components.world_id {
+            return Err(SnapshotError::DifferentWorld);
+        }
+        components.entities = self.entities;
+        components.vacant = self.vacant.clone();
+        let alive = components.alive_mask();
+        for (idx, column) in components.items.iter_mut().enumerate() {
+            match self.columns.get(idx) {
+                Some(Some(saved)) => {
+                    let clone = components.info[idx].clone.unwrap();
+                    *column = saved.iter()
+                        .map(|slot| slot.as_ref().map(|c| clone(c.as_ref())))
+                        .collect();
+                }
+                //registered after the snapshot, nothing could have had it back then
+                None if components.info[idx].is_cloneable() => column.clear(),
+                //not part of the snapshot, only drop what belongs to entities that didn't exist yet
+                _ => {
+                    column.truncate(self.entities);
+                    for (entity_id, slot) in column.iter_mut().enumerate() {
+                        if !alive[entity_id] {
+                            *slot = None;
+                        }
+                    }
+                }
+            }
+        }
+        components.invalidate_indexes();
+        components.changes.reset();
+        Ok(())
+    }
+}
+
+/// Full copy of the rollback state of a world, see [`crate::world::World::snapshot`]
+pub struct WorldSnapshot {
+    pub(crate) components: ComponentsSnapshot,
+    pub(crate) resources: ResourcesSnapshot,
+}
+
+/// Hashes the live entity ids followed by every hashable component, in component id then entity id order.
+/// Peers registering the same components in the same order get the same checksum for the same state
+pub(crate) fn checksum(components: &Components) -> u64 {
+    let mut hasher = StableHasher::new();
+    for (entity_id, alive) in components.alive_mask().into_iter().enumerate() {
+        if alive {
+            hasher.write_usize(entity_id);
+        }
+    }
+
+    for (idx, (column, info)) in components.items.iter().zip(components.info.iter()).enumerate() {
+        let Some(hash) = info.hash else { continue };
+        hasher.write_usize(idx);
+        for (entity_id, slot) in column.iter().enumerate() {
+            if let Some(component) = slot {
+                hasher.write_usize(entity_id);
+                hash(component.as_ref(), &mut hasher);
+            }
+        }
+    }
+    hasher.finish()
+}
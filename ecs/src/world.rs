@@ -1,44 +1,77 @@
-use std::any::{Any, TypeId};
-use std::marker::PhantomData;
+use std::any::Any;
+use std::hash::Hash;
+use std::time::Instant;
 
-use crate::component::{Components, Query, Fetch};
+use serde::de::DeserializeOwned;
+
+use crate::component::{check_unique, ComponentId, Components, Query, Fetch};
+use crate::diagnostics::{SystemTimings, WorldDiagnostics};
+use crate::dynamic::{ComponentDescriptor, RegisterError};
 use crate::entity_builder::{EntityBuilder, EntityId};
+use crate::prefab::{Parent, Prefab, PrefabError, PrefabOverrides, PrefabRegistry};
 use crate::resource::Resources;
+use crate::snapshot::{ComponentsSnapshot, SnapshotError, WorldSnapshot};
 
 #[derive(Default)]
 pub struct World {
     pub resources: Resources,
     pub components: Components,
+    prefabs: PrefabRegistry,
+    timings: Option<SystemTimings>,
 }
 
 pub fn builder() -> WorldBuilder {
     WorldBuilder {
         components: Default::default(),
+        prefabs: Default::default(),
     }
 }
 
 #[derive(Default)]
 pub struct WorldBuilder {
     components: Components,
+    prefabs: PrefabRegistry,
 }
 
 impl WorldBuilder {
-    pub fn register<C: ?Sized + 'static>(mut self) -> Self {
-        let type_id = TypeId::of::<C>();
-        if !self.components.items.contains_key(&type_id) {
-            let name = std::any::type_name::<C>();
-            println!("Registering {name}");
-            self.components.items.insert(type_id, vec![]);
-        }
+    pub fn register<C: 'static>(mut self) -> Self {
+        self.components.register::<C>();
+        self
+    }
 
+    /// Registers a component that can be cloned into other worlds with [`World::clone_entity_to`]
+    pub fn register_cloneable<C: Clone + 'static>(mut self) -> Self {
+        self.components.register_cloneable::<C>();
         self
     }
 
 
+    /// Registers a component that is part of [`World::checksum`]
+    pub fn register_hashable<C: Hash + 'static>(mut self) -> Self {
+        self.components.register_hashable::<C>();
+        self
+    }
+
+    /// Registers an index over a key taken from component `C`, used by [`World::find_by`]
+    pub fn index<C: 'static, K: Hash + Eq + Clone + 'static>(mut self, key: fn(&C) -> K) -> Self {
+        self.components.add_index(key);
+        self
+    }
+
+    /// Registers a component that can be used in prefab files under the given name
+    pub fn register_prefab<C: DeserializeOwned + 'static>(mut self, name: &str) -> Self {
+        let id = self.components.register::<C>();
+        self.components.register::<Parent>();
+        self.prefabs.register::<C>(name, id);
+        self
+    }
+
     pub fn build(self) -> World {
         World {
             resources: Resources::default(),
             components: self.components,
+            prefabs: self.prefabs,
+            timings: None,
         }
     }
 }
@@ -49,6 +82,12 @@ impl World {
         self
     }
 
+    /// Adds a resource that is captured by [`World::snapshot`]
+    pub fn add_cloneable_resource<T: Any + Clone>(&mut self, resource: T) -> &mut Self {
+        self.resources.add_cloneable_resource(resource);
+        self
+    }
+
     pub fn get_resource<T: Any>(&self) -> Option<&T> {
         self.resources.get_resource()
     }
@@ -61,7 +100,7 @@ impl World {
         self.resources.remove_resource()
     }
 
-    pub fn new_entity(&mut self) -> EntityBuilder {
+    pub fn new_entity(&mut self) -> EntityBuilder<'_> {
         let entity_id = self.components.new_entity();
         EntityBuilder {
             id: entity_id,
@@ -73,32 +112,148 @@ impl World {
         self.components.get_component(entity_id)
     }
 
+    /// Moves the entity and all its components into `target`, returning its id in the target world.
+    /// Panics if any of the components is not registered in `target`
+    pub fn move_entity_to(&mut self, entity_id: EntityId, target: &mut World) -> EntityId {
+        let mapping = self.component_mapping(entity_id, target);
+        let components = self.components.take_entity(entity_id);
+        target.components.spawn_boxed(remap(components, &mapping))
+    }
+
+    /// Clones the entity with all its components into `target`, returning the id of the copy.
+    /// Every component of the entity must have been registered with `register_cloneable`
+    pub fn clone_entity_to(&self, entity_id: EntityId, target: &mut World) -> EntityId {
+        let mapping = self.component_mapping(entity_id, target);
+        let components = self.components.clone_entity(entity_id);
+        target.components.spawn_boxed(remap(components, &mapping))
+    }
+
+    //component ids are per world, resolved upfront so a failed transfer doesn't leave a half built entity behind
+    fn component_mapping(&self, entity_id: EntityId, target: &World) -> Vec<Option<ComponentId>> {
+        self.components.info.iter()
+            .enumerate()
+            .map(|(idx, info)| {
+                if !self.components.has_component(entity_id, ComponentId(idx)) {
+                    return None;
+                }
+                let id = target.components.resolve(info)
+                    .unwrap_or_else(|| panic!("Component {} not registered in target world", info.name));
+                Some(id)
+            })
+            .collect()
+    }
+
+    /// Spawns the prefab and its children, children get a [`Parent`] component pointing to the entity they were spawned under.
+    /// Nothing is spawned if any of the component values fails to deserialize
+    pub fn spawn_prefab(&mut self, prefab: &Prefab) -> Result<EntityId, PrefabError> {
+        self.spawn_prefab_with(prefab, PrefabOverrides::default())
+    }
 
-    pub fn query<Tuple>(&mut self) -> Query<Tuple> {
+    pub fn spawn_prefab_with(&mut self, prefab: &Prefab, overrides: PrefabOverrides) -> Result<EntityId, PrefabError> {
+        self.prefabs.spawn(&mut self.components, prefab, overrides)
+    }
+
+    /// Registers a component defined at runtime, it can be registered after entities were created
+    pub fn register_dynamic(&mut self, descriptor: ComponentDescriptor) -> Result<ComponentId, RegisterError> {
+        self.components.register_dynamic(descriptor)
+    }
+
+
+    pub fn query<Tuple>(&mut self) -> Query<'_, Tuple>
+        where
+            Tuple: for<'a> Fetch<'a>
+    {
         self.components.query::<Tuple>()
     }
 
-    pub fn run_system<C, T>(&mut self, f: fn(<T as Fetch<'_>>::Data))
+    /// Finds the entity whose component `C` maps to `key` in the index registered with [`WorldBuilder::index`],
+    /// the lowest entity id if several do. `None` as well when no such index was registered
+    pub fn find_by<C: Any, K: Hash + Eq + Clone + 'static>(&mut self, key: &K) -> Option<EntityId> {
+        self.components.index::<C, K>()?.get(key)
+    }
+
+    /// Panics if the same component appears more than once in `T`, same as [`World::query`]
+    pub fn run_system<C, T>(&mut self, mut f: impl FnMut(<T as Fetch<'_>>::Data))
         where
             T: for<'a> Fetch<'a>,
     {
+        check_unique::<T>();
+        let start = Instant::now();
         for entity_id in 0..self.components.entities {
             if let Some(component) = T::fetch(&mut self.components, entity_id) {
                 (f)(component)
             }
         }
+        self.record_timing(std::any::type_name_of_val(&f), start);
     }
-    
-    pub fn run_system_with_context<C, T>(&mut self, ctx: &mut C, f: fn(&mut C, <T as Fetch<'_>>::Data))
+
+    pub fn run_system_with_context<C, T>(&mut self, ctx: &mut C, mut f: impl FnMut(&mut C, <T as Fetch<'_>>::Data))
         where
             T: for<'a> Fetch<'a>,
     {
+        check_unique::<T>();
+        let start = Instant::now();
         for entity_id in 0..self.components.entities {
             if let Some(component) = T::fetch(&mut self.components, entity_id) {
                 (f)(ctx, component)
             }
         }
+        self.record_timing(std::any::type_name_of_val(&f), start);
+    }
+
+    /// Copies every component registered as cloneable and every cloneable resource
+    pub fn snapshot(&self) -> WorldSnapshot {
+        WorldSnapshot {
+            components: ComponentsSnapshot::take(&self.components),
+            resources: self.resources.snapshot(),
+        }
+    }
+
+    /// Puts the world back to the state it had when the snapshot was taken, the snapshot can be restored more than once.
+    /// Components that are not cloneable are kept, except for entities that didn't exist yet.
+    /// Fails without changing anything if the snapshot was taken from another world
+    pub fn restore(&mut self, snapshot: &WorldSnapshot) -> Result<(), SnapshotError> {
+        snapshot.components.restore(&mut self.components)?;
+        self.resources.restore(&snapshot.resources);
+        Ok(())
     }
+
+    /// Deterministic hash over the live entity ids and the components registered as hashable
+    pub fn checksum(&self) -> u64 {
+        crate::snapshot::checksum(&self.components)
+    }
+
+    pub fn diagnostics(&self) -> WorldDiagnostics {
+        WorldDiagnostics::collect(&self.components)
+    }
+
+    /// Starts recording how long each `run_system` call takes, systems are identified by their function name
+    pub fn enable_system_timings(&mut self) -> &mut Self {
+        if self.timings.is_none() {
+            self.timings = Some(SystemTimings::new());
+        }
+        self
+    }
+
+    pub fn system_timings(&self) -> Option<&SystemTimings> {
+        self.timings.as_ref()
+    }
+
+    pub fn take_system_timings(&mut self) -> Option<SystemTimings> {
+        self.timings.take()
+    }
+
+    fn record_timing(&mut self, name: &str, start: Instant) {
+        if let Some(timings) = self.timings.as_mut() {
+            timings.record(name, start, start.elapsed());
+        }
+    }
+}
+
+fn remap(components: Vec<(ComponentId, Box<dyn Any>)>, mapping: &[Option<ComponentId>]) -> Vec<(ComponentId, Box<dyn Any>)> {
+    components.into_iter()
+        .map(|(id, component)| (mapping[id.0].unwrap(), component))
+        .collect()
 }
 
 
@@ -106,13 +261,15 @@ impl World {
 mod tests {
     use std::marker::PhantomData;
     use crate::component::LendingIterator;
+    use crate::prefab::PrefabValue;
+    use crate::query_state::{QueryState, With, Without};
 
     use super::*;
 
-    #[derive(Debug, Default, Eq, PartialEq)]
+    #[derive(Debug, Default, Eq, PartialEq, serde::Deserialize)]
     struct Speed(u32);
 
-    #[derive(Debug, Default, Eq, PartialEq)]
+    #[derive(Debug, Default, Eq, PartialEq, serde::Deserialize)]
     struct Health(u32);
 
 
@@ -186,10 +343,521 @@ mod tests {
 
     }
 
-    fn my_system(ctx: &mut Ctx, mut iter: Query<(Speed, Health)>) {
+    fn my_system(_ctx: &mut Ctx, mut iter: Query<(Speed, Health)>) {
         while let Some((speed, health)) = iter.next() {
             println!("{speed:?} {health:?}");
         }
     }
 
+    #[test]
+    fn test_diagnostics() {
+        let mut world = builder()
+            .register::<Speed>()
+            .register::<Health>()
+            .build();
+
+        let first = world.new_entity().with_component(Speed(1)).id();
+        world.new_entity().with_component(Speed(2)).with_component(Health(10));
+        world.components.remove_entity(first);
+
+        let diagnostics = world.diagnostics();
+        assert_eq!(diagnostics.live_entities, 1);
+        assert_eq!(diagnostics.vacant_entities, 1);
+
+        let speed = diagnostics.components.iter().find(|c| c.name.ends_with("Speed")).unwrap();
+        assert_eq!(speed.count, 1);
+        assert_eq!(speed.slots, 2);
+        assert!(speed.bytes >= std::mem::size_of::<Speed>());
+    }
+
+    #[test]
+    #[should_panic(expected = "is not alive")]
+    fn test_remove_entity_twice() {
+        let mut world = builder().register::<Speed>().build();
+        let a = world.new_entity().with_component(Speed(1)).id();
+        world.new_entity();
+        world.components.remove_entity(a);
+        assert_eq!(world.components.live_entities(), 1);
+        world.components.remove_entity(a);
+    }
+
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    struct Ghost(&'static str);
+
+    #[test]
+    fn test_move_entity_between_worlds() {
+        let mut simulation = builder()
+            .register::<Speed>()
+            .register::<Health>()
+            .build();
+        let mut preview = builder()
+            .register::<Speed>()
+            .register::<Health>()
+            .build();
+
+        let id = simulation.new_entity().with_component(Speed(3)).with_component(Health(50)).id();
+        let moved = simulation.move_entity_to(id, &mut preview);
+
+        assert!(!simulation.components.is_alive(id));
+        assert_eq!(simulation.get_component::<Speed>(id), None);
+        assert_eq!(preview.get_component::<Speed>(moved), Some(&mut Speed(3)));
+        assert_eq!(preview.get_component::<Health>(moved), Some(&mut Health(50)));
+    }
+
+    #[test]
+    fn test_clone_entity_between_worlds() {
+        let mut simulation = builder()
+            .register_cloneable::<Ghost>()
+            .build();
+        let mut preview = builder()
+            .register_cloneable::<Ghost>()
+            .build();
+
+        let id = simulation.new_entity().with_component(Ghost("tower")).id();
+        let copy = simulation.clone_entity_to(id, &mut preview);
+
+        assert_eq!(simulation.get_component::<Ghost>(id), Some(&mut Ghost("tower")));
+        assert_eq!(preview.get_component::<Ghost>(copy), Some(&mut Ghost("tower")));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not cloneable")]
+    fn test_clone_entity_requires_cloneable_components() {
+        let mut simulation = builder().register::<Speed>().build();
+        let mut preview = builder().register::<Speed>().build();
+
+        let id = simulation.new_entity().with_component(Speed(1)).id();
+        simulation.clone_entity_to(id, &mut preview);
+    }
+
+    #[test]
+    fn test_dynamic_components() {
+        let mut world = builder()
+            .register::<Speed>()
+            .build();
+        let position = world.register_dynamic(ComponentDescriptor::new("lua.Position", 8, 4)).unwrap();
+        let speed = world.components.component_id::<Speed>().unwrap();
+        assert_eq!(world.components.component_id_by_name("lua.Position"), Some(position));
+
+        let moving = world.new_entity().with_component(Speed(2)).id();
+        let idle = world.new_entity().id();
+        for entity in [moving, idle] {
+            let value = [1.0f32, 5.0];
+            unsafe { world.components.add_component_by_id(entity, position, value.as_ptr() as *const u8) };
+        }
+
+        for (_, ptrs) in world.components.query_dynamic(&[position, speed]) {
+            unsafe {
+                let position = &mut *(ptrs[0].as_ptr() as *mut [f32; 2]);
+                let speed = &*(ptrs[1].as_ptr() as *const Speed);
+                position[0] += speed.0 as f32;
+            }
+        }
+
+        let read = |world: &mut World, entity| unsafe {
+            *(world.components.get_component_ptr(entity, position).unwrap().as_ptr() as *const [f32; 2])
+        };
+        assert_eq!(read(&mut world, moving), [3.0, 5.0]);
+        assert_eq!(read(&mut world, idle), [1.0, 5.0]);
+        assert_eq!(world.components.query_dynamic(&[position]).count(), 2);
+    }
+
+    #[test]
+    fn test_register_dynamic_errors() {
+        let mut world = builder().build();
+        let invalid = world.register_dynamic(ComponentDescriptor::new("lua.Broken", 8, 3));
+        assert!(matches!(invalid, Err(RegisterError::InvalidLayout { size: 8, align: 3, .. })));
+        assert_eq!(world.components.component_id_by_name("lua.Broken"), None);
+
+        let position = world.register_dynamic(ComponentDescriptor::new("lua.Position", 8, 4)).unwrap();
+        assert_eq!(world.register_dynamic(ComponentDescriptor::new("lua.Position", 8, 4)), Ok(position));
+        assert_eq!(world.register_dynamic(ComponentDescriptor::new("lua.Position", 12, 4)), Err(RegisterError::Conflict("lua.Position".to_string())));
+    }
+
+    static DROPPED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    unsafe fn count_drop(_: *mut u8) {
+        DROPPED.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_dynamic_component_drop() {
+        let mut world = builder().build();
+        let handle = world.register_dynamic(ComponentDescriptor::new("lua.Handle", 4, 4).with_drop(count_drop)).unwrap();
+        assert!(!world.components.component_info(handle).is_cloneable());
+
+        let entity = world.new_entity().id();
+        let value = 7u32;
+        unsafe { world.components.add_component_by_id(entity, handle, &value as *const u32 as *const u8) };
+        world.components.remove_entity(entity);
+
+        assert_eq!(DROPPED.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    const GOBLIN: &str = r#"(
+        components: {
+            "Health": Health(30),
+            "Speed": Speed(2),
+        },
+        children: [
+            (components: { "Health": Health(5) }),
+        ],
+    )"#;
+
+    fn prefab_world() -> World {
+        builder()
+            .register_prefab::<Health>("Health")
+            .register_prefab::<Speed>("Speed")
+            .build()
+    }
+
+    #[test]
+    fn test_spawn_prefab() {
+        let mut world = prefab_world();
+        let prefab = Prefab::from_ron(GOBLIN).unwrap();
+
+        let first = world.spawn_prefab(&prefab).unwrap();
+        let second = world.spawn_prefab_with(&prefab, PrefabOverrides::new().with(Health(99))).unwrap();
+
+        assert_eq!(world.get_component::<Health>(first), Some(&mut Health(30)));
+        assert_eq!(world.get_component::<Speed>(first), Some(&mut Speed(2)));
+        assert_eq!(world.get_component::<Health>(second), Some(&mut Health(99)));
+        assert_eq!(world.get_component::<Parent>(first), None);
+
+        let mut children = vec![];
+        let mut query = world.query::<(Health, Parent)>();
+        while let Some((health, parent)) = query.next() {
+            children.push((health.0, parent.0));
+        }
+        assert_eq!(children, vec![(5, first), (5, second)]);
+    }
+
+    #[test]
+    fn test_spawn_json_prefab_with_value_override() {
+        let mut world = prefab_world();
+        let prefab = Prefab::from_json(r#"{"components": {"Health": 10, "Speed": 1}}"#).unwrap();
+
+        let overrides = PrefabOverrides::new().with_value("Speed", PrefabValue::json("7"));
+        let id = world.spawn_prefab_with(&prefab, overrides).unwrap();
+
+        assert_eq!(world.get_component::<Health>(id), Some(&mut Health(10)));
+        assert_eq!(world.get_component::<Speed>(id), Some(&mut Speed(7)));
+    }
+
+    #[test]
+    fn test_invalid_prefab_spawns_nothing() {
+        let mut world = prefab_world();
+        let prefab = Prefab::from_ron(r#"(components: { "Health": Health(1), "Mana": Mana(3) })"#).unwrap();
+
+        let result = world.spawn_prefab(&prefab);
+        assert!(matches!(result, Err(PrefabError::UnknownComponent(name)) if name == "Mana"));
+        assert_eq!(world.components.live_entities(), 0);
+    }
+
+    #[test]
+    fn test_spawn_prefab_without_prefab_components() {
+        let mut world = builder().register::<Health>().build();
+        let prefab = Prefab::from_ron("(components: {})").unwrap();
+
+        let result = world.spawn_prefab(&prefab);
+        assert!(matches!(result, Err(PrefabError::UnknownComponent(name)) if name == "Parent"));
+        assert_eq!(world.components.live_entities(), 0);
+    }
+
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    struct NetworkId(u64);
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Z(f32);
+
+    #[test]
+    fn test_find_by_index() {
+        let mut world = builder()
+            .register::<Speed>()
+            .index::<NetworkId, u64>(|id| id.0)
+            .build();
+
+        let a = world.new_entity().with_component(NetworkId(10)).id();
+        let b = world.new_entity().with_component(NetworkId(20)).with_component(Speed(1)).id();
+        assert_eq!(world.find_by::<NetworkId, u64>(&10), Some(a));
+        assert_eq!(world.find_by::<NetworkId, u64>(&20), Some(b));
+
+        //mutation through a query
+        let mut query = world.query::<(NetworkId, Speed)>();
+        while let Some((id, _)) = query.next() {
+            id.0 = 21;
+        }
+        assert_eq!(world.find_by::<NetworkId, u64>(&20), None);
+        assert_eq!(world.find_by::<NetworkId, u64>(&21), Some(b));
+
+        //mutation through get_component
+        world.get_component::<NetworkId>(a).unwrap().0 = 11;
+        assert_eq!(world.find_by::<NetworkId, u64>(&11), Some(a));
+
+        world.components.remove_component::<NetworkId>(a);
+        world.components.remove_entity(b);
+        assert_eq!(world.find_by::<NetworkId, u64>(&11), None);
+        assert_eq!(world.find_by::<NetworkId, u64>(&21), None);
+    }
+
+    #[test]
+    fn test_find_by_shared_key() {
+        let mut world = builder()
+            .index::<NetworkId, u64>(|id| id.0)
+            .build();
+
+        let a = world.new_entity().with_component(NetworkId(10)).id();
+        let b = world.new_entity().with_component(NetworkId(10)).id();
+        //updated last, still not the one returned
+        world.get_component::<NetworkId>(b).unwrap().0 = 10;
+        assert_eq!(world.find_by::<NetworkId, u64>(&10), Some(a));
+        let all: Vec<_> = world.components.index::<NetworkId, u64>().unwrap().get_all(&10).collect();
+        assert_eq!(all, vec![a, b]);
+
+        world.components.remove_entity(a);
+        assert_eq!(world.find_by::<NetworkId, u64>(&10), Some(b));
+        world.get_component::<NetworkId>(b).unwrap().0 = 20;
+        assert_eq!(world.find_by::<NetworkId, u64>(&10), None);
+        assert_eq!(world.find_by::<NetworkId, u64>(&20), Some(b));
+    }
+
+    #[test]
+    fn test_find_by_without_index() {
+        let mut world = builder().register::<NetworkId>().build();
+        world.new_entity().with_component(NetworkId(10));
+        assert_eq!(world.find_by::<NetworkId, u64>(&10), None);
+    }
+
+    #[test]
+    fn test_query_sorted_by_key() {
+        let mut world = builder()
+            .register::<Z>()
+            .register::<Health>()
+            .build();
+
+        for (z, health) in [(3.0, 1), (1.0, 2), (2.0, 3)] {
+            world.new_entity().with_component(Z(z)).with_component(Health(health));
+        }
+        world.new_entity().with_component(Z(0.0));
+
+        let mut sorted = vec![];
+        let mut query = world.query::<(Health,)>().sorted_by_key(|z: &Z| (z.0 * 100.0) as i32);
+        while let Some((health,)) = query.next() {
+            sorted.push(health.0);
+        }
+        assert_eq!(sorted, vec![2, 3, 1]);
+    }
+
+    #[test]
+    fn test_query_std_iterator() {
+        let mut world = builder()
+            .register::<Speed>()
+            .register::<Health>()
+            .build();
+        for i in 0..4 {
+            world.new_entity().with_component(Speed(i)).with_component(Health(i * 10));
+        }
+
+        let collected: Vec<(&mut Speed, &mut Health)> = world.query::<(Speed, Health)>()
+            .into_iter()
+            .filter(|(speed, _)| speed.0 % 2 == 0)
+            .collect();
+        assert_eq!(collected.len(), 2);
+        for (speed, health) in collected {
+            speed.0 += health.0;
+        }
+
+        let speeds: Vec<u32> = world.query::<(Speed,)>().into_iter().map(|(s,)| s.0).collect();
+        assert_eq!(speeds, vec![0, 1, 22, 3]);
+
+        let healths: Vec<u32> = world.query::<(Health,)>().into_iter().map(|(h,)| h.0).collect();
+        let sorted: Vec<u32> = world.query::<(Health,)>()
+            .sorted_by_key(|h: &Health| std::cmp::Reverse(h.0))
+            .into_iter()
+            .map(|(h,)| h.0)
+            .collect();
+        assert_eq!(sorted, vec![30, 20, 10, 0]);
+        let pairs: Vec<(u32, u32)> = speeds.into_iter().zip(healths).collect();
+        assert_eq!(pairs[2], (22, 20));
+    }
+
+    #[test]
+    #[should_panic(expected = "requested more than once")]
+    fn test_query_rejects_duplicate_components() {
+        let mut world = builder().register::<Speed>().build();
+        world.query::<(Speed, Speed)>();
+    }
+
+    #[test]
+    #[should_panic(expected = "requested more than once")]
+    fn test_run_system_rejects_duplicate_components() {
+        let mut world = builder().register::<Speed>().build();
+        world.new_entity().with_component(Speed(1));
+        world.run_system::<(), (Speed, Speed)>(|(a, b)| std::mem::swap(a, b));
+    }
+
+    #[test]
+    #[should_panic(expected = "requested more than once")]
+    fn test_run_system_with_context_rejects_duplicate_components() {
+        let mut world = builder().register::<Speed>().build();
+        world.new_entity().with_component(Speed(1));
+        world.run_system_with_context::<(), (Speed, Speed)>(&mut (), |_, (a, b)| std::mem::swap(a, b));
+    }
+
+    #[derive(Debug, Clone, Hash, Eq, PartialEq)]
+    struct Position(i32, i32);
+
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    struct Frame(u64);
+
+    fn rollback_world() -> World {
+        builder()
+            .register_cloneable::<Position>()
+            .register_hashable::<Position>()
+            .register::<Speed>()
+            .build()
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut world = rollback_world();
+        world.add_cloneable_resource(Frame(1));
+        let player = world.new_entity().with_component(Position(0, 0)).with_component(Speed(1)).id();
+
+        let snapshot = world.snapshot();
+        let checksum = world.checksum();
+
+        world.get_component::<Position>(player).unwrap().0 = 10;
+        world.get_resource_mut::<Frame>().unwrap().0 = 2;
+        let spawned = world.new_entity().with_component(Position(5, 5)).with_component(Speed(9)).id();
+        assert_ne!(world.checksum(), checksum);
+
+        for _ in 0..2 {
+            world.restore(&snapshot).unwrap();
+            assert_eq!(world.checksum(), checksum);
+            assert_eq!(world.get_component::<Position>(player), Some(&mut Position(0, 0)));
+            assert_eq!(world.get_resource::<Frame>(), Some(&Frame(1)));
+            assert!(!world.components.is_alive(spawned));
+            assert_eq!(world.get_component::<Speed>(spawned), None);
+            //not cloneable, kept as is
+            assert_eq!(world.get_component::<Speed>(player), Some(&mut Speed(1)));
+            world.get_component::<Position>(player).unwrap().1 = 3;
+        }
+    }
+
+    #[test]
+    fn test_restore_rejects_other_world() {
+        let mut world = rollback_world();
+        world.add_cloneable_resource(Frame(1));
+        let player = world.new_entity().with_component(Position(0, 0)).id();
+
+        let mut other = rollback_world();
+        other.new_entity().with_component(Position(7, 7));
+        let snapshot = other.snapshot();
+
+        assert_eq!(world.restore(&snapshot), Err(SnapshotError::DifferentWorld));
+        assert_eq!(world.get_component::<Position>(player), Some(&mut Position(0, 0)));
+        assert_eq!(world.get_resource::<Frame>(), Some(&Frame(1)));
+    }
+
+    #[test]
+    fn test_checksum_is_deterministic() {
+        let mut a = rollback_world();
+        let mut b = rollback_world();
+        for world in [&mut a, &mut b] {
+            world.new_entity().with_component(Position(1, 2));
+            world.new_entity().with_component(Position(3, 4)).with_component(Speed(1));
+        }
+        b.get_component::<Speed>(1).unwrap().0 = 100;
+        assert_eq!(a.checksum(), b.checksum());
+
+        b.get_component::<Position>(1).unwrap().0 = 4;
+        assert_ne!(a.checksum(), b.checksum());
+    }
+
+    #[derive(Debug)]
+    struct Frozen;
+
+    #[test]
+    fn test_query_state_tracks_changes() {
+        let mut world = builder()
+            .register::<Speed>()
+            .register::<Health>()
+            .register::<Frozen>()
+            .build();
+        let mut moving = QueryState::<(Speed,), (With<Health>, Without<Frozen>)>::new();
+
+        let a = world.new_entity().with_component(Speed(1)).with_component(Health(1)).id();
+        let b = world.new_entity().with_component(Speed(2)).with_component(Health(1)).id();
+        world.new_entity().with_component(Speed(3));
+        assert_eq!(moving.iter(&mut world).count(), 2);
+
+        for (speed,) in moving.iter(&mut world) {
+            speed.0 *= 10;
+        }
+        assert_eq!(world.get_component::<Speed>(a), Some(&mut Speed(10)));
+
+        world.components.add_component(b, Frozen);
+        let c = world.new_entity().with_component(Speed(4)).with_component(Health(1)).id();
+        let speeds: Vec<u32> = moving.iter(&mut world).map(|(s,)| s.0).collect();
+        assert_eq!(speeds, vec![10, 4]);
+
+        world.components.remove_entity(a);
+        world.components.remove_component::<Frozen>(b);
+        let speeds: Vec<u32> = moving.iter(&mut world).map(|(s,)| s.0).collect();
+        assert_eq!(speeds, vec![20, 4]);
+        assert_eq!(moving.len(), 2);
+
+        //vacant slot reused by a new entity without the components
+        let reused = world.new_entity().id();
+        assert_eq!(reused, a);
+        assert_eq!(moving.iter(&mut world).count(), 2);
+        assert!(world.components.is_alive(c));
+    }
+
+    #[test]
+    fn test_query_state_rebuilds_for_other_world() {
+        let mut first = builder().register::<Speed>().build();
+        let mut second = builder().register::<Speed>().build();
+        first.new_entity().with_component(Speed(1));
+        for i in 0..3 {
+            second.new_entity().with_component(Speed(i));
+        }
+
+        let mut state = QueryState::<(Speed,)>::new();
+        assert_eq!(state.iter(&mut first).count(), 1);
+        assert_eq!(state.iter(&mut second).count(), 3);
+
+        //restoring a snapshot replaces whole columns
+        let snapshot = first.snapshot();
+        first.new_entity().with_component(Speed(2));
+        assert_eq!(state.iter(&mut first).count(), 2);
+        first.restore(&snapshot).unwrap();
+        assert_eq!(state.iter(&mut first).count(), 1);
+    }
+
+    fn move_system((speed,): (&mut Speed,)) {
+        speed.0 += 1;
+    }
+
+    #[test]
+    fn test_system_timings() {
+        let mut world = builder()
+            .register::<Speed>()
+            .build();
+        world.new_entity().with_component(Speed(1));
+
+        world.enable_system_timings();
+        world.run_system::<(), (Speed,)>(move_system);
+        world.run_system::<(), (Speed,)>(move_system);
+
+        let timings = world.system_timings().unwrap();
+        assert_eq!(timings.records().len(), 2);
+        assert!(timings.records()[0].name.ends_with("move_system"));
+
+        let trace = timings.to_chrome_trace();
+        assert!(trace.starts_with("{\"traceEvents\":[{\"name\":\""));
+        assert_eq!(trace.matches("\"ph\":\"X\"").count(), 2);
+    }
+
 }
\ No newline at end of file
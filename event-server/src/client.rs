@@ -0,0 +1,645 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, watch, Notify};
+
+use crate::codec::{encode_event, Codec, DefaultCodec};
+use crate::frame::{read_frame, write_frame, FrameKind};
+use crate::listener::MemoryConnector;
+use crate::queue::{EventQueue, OverflowPolicy, QueueStats, SendError};
+use crate::rpc::{decode_response, encode_request, Pending, RequestError};
+
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Stream for S {}
+
+/// Plain or TLS connection, decided at runtime by the builder
+type BoxedStream = Box<dyn Stream>;
+
+/// Client side of an `EventServer`, uses the same framing and has to use the same codec
+pub struct EventClient<T> {
+    queue: Arc<EventQueue<Outbound<T>>>,
+    pending: Arc<Pending<T>>,
+    rx: mpsc::UnboundedReceiver<T>,
+    state_rx: watch::Receiver<ClientState>,
+}
+
+/// Requests share the queue with events so they go out in the order they were made
+enum Outbound<T> {
+    Event(T),
+    Request(u64, T),
+}
+
+impl<T> Outbound<T> {
+    fn into_event(self) -> T {
+        match self {
+            Outbound::Event(event) | Outbound::Request(_, event) => event,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientState {
+    Connected,
+    /// The link dropped, events sent meanwhile are queued and written once it is back
+    Reconnecting,
+    /// Closed for good, by either side or because reconnecting gave up
+    Disconnected,
+}
+
+/// Exponential backoff between reconnection attempts.
+/// Each delay is randomized between half and all of it so clients dropped together don't come back together
+#[derive(Debug, Clone)]
+pub struct Reconnect {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Gives up after this many failed attempts in a row, `None` retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Reconnect {
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        delay / 2 + jitter(delay / 2)
+    }
+}
+
+fn jitter(max: Duration) -> Duration {
+    //randomly keyed on every call, good enough to spread clients apart without pulling in an rng
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+pub struct ClientBuilder<T, C = DefaultCodec> {
+    codec: C,
+    max_event_size: usize,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    reconnect: Option<Reconnect>,
+    on_reconnect: Arc<dyn Fn(u32) + Send + Sync>,
+    credentials: Option<Vec<u8>>,
+    #[cfg(feature = "tls")]
+    tls: Option<(Arc<tokio_rustls::rustls::ClientConfig>, String)>,
+    _m: PhantomData<fn() -> T>,
+}
+
+#[cfg(feature = "bincode")]
+impl<T> ClientBuilder<T, crate::codec::BincodeCodec>
+    where
+        T: Send + 'static,
+        crate::codec::BincodeCodec: Codec<T>
+{
+    pub fn bincode_config(self, config: bincode::config::Configuration) -> Self {
+        self.codec(crate::codec::BincodeCodec::new(config))
+    }
+}
+
+impl<T, C> ClientBuilder<T, C> {
+    /// Has to be the codec the server uses
+    pub fn codec<C2: Codec<T>>(self, codec: C2) -> ClientBuilder<T, C2> {
+        ClientBuilder {
+            codec,
+            max_event_size: self.max_event_size,
+            queue_capacity: self.queue_capacity,
+            overflow_policy: self.overflow_policy,
+            reconnect: self.reconnect,
+            on_reconnect: self.on_reconnect,
+            credentials: self.credentials,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            _m: PhantomData,
+        }
+    }
+}
+
+impl<T, C> ClientBuilder<T, C>
+    where
+        T: Send + 'static,
+        C: Codec<T>
+{
+    /// Largest encoded event accepted or sent, frames announcing a bigger size close the connection
+    pub fn max_event_size(mut self, max_event_size: usize) -> Self {
+        self.max_event_size = max_event_size;
+        self
+    }
+
+    /// Number of events waiting to be written before the overflow policy kicks in, 1024 by default, has to be at least 1
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        assert!(queue_capacity > 0, "Queue capacity must be at least 1");
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    /// `DropOldest` by default, `Disconnect` closes the client for good
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Reconnects when the link drops instead of disconnecting, the queued events are kept meanwhile.
+    /// An event already handed to the socket when the link dropped may still be lost
+    pub fn reconnect(mut self, reconnect: Reconnect) -> Self {
+        self.reconnect = Some(reconnect);
+        self
+    }
+
+    /// Called with the number of attempts it took, once the connection is back and before queued events are written
+    pub fn on_reconnect<F>(mut self, on_reconnect: F) -> Self
+        where
+            F: Fn(u32) + 'static + Send + Sync
+    {
+        self.on_reconnect = Arc::new(on_reconnect);
+        self
+    }
+
+    /// Sent as the first frame of every connection, for servers that authenticate clients.
+    /// A rejected connection fails with `PermissionDenied` carrying the server's reason, and so do reconnects
+    pub fn auth(mut self, credentials: impl Into<Vec<u8>>) -> Self {
+        self.credentials = Some(credentials.into());
+        self
+    }
+
+    /// Connects over TLS, `server_name` is the name the server certificate is checked against.
+    /// See [`crate::tls::client_config`] to trust the certificates of a PEM file
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<tokio_rustls::rustls::ClientConfig>, server_name: impl Into<String>) -> Self {
+        self.tls = Some((config, server_name.into()));
+        self
+    }
+
+    /// Connects once, reconnection only applies after the first connection succeeded
+    pub async fn connect<A: ToSocketAddrs>(self, addr: A) -> io::Result<EventClient<T>> {
+        let addrs: Vec<_> = tokio::net::lookup_host(addr).await?.collect();
+        self.start(Target::Tcp(addrs)).await
+    }
+
+    /// Same as `connect`, to a server listening on a Unix domain socket
+    #[cfg(unix)]
+    pub async fn connect_unix(self, path: impl AsRef<Path>) -> io::Result<EventClient<T>> {
+        self.start(Target::Unix(path.as_ref().to_path_buf())).await
+    }
+
+    /// Same as `connect`, to a server in the same process serving a [`crate::listener::MemoryListener`]
+    pub async fn connect_memory(self, connector: &MemoryConnector) -> io::Result<EventClient<T>> {
+        self.start(Target::Memory(connector.clone())).await
+    }
+
+    async fn start(self, target: Target) -> io::Result<EventClient<T>> {
+        #[cfg(feature = "tls")]
+        let tls = match self.tls {
+            Some((config, server_name)) => {
+                let server_name = tokio_rustls::rustls::pki_types::ServerName::try_from(server_name)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                Some((tokio_rustls::TlsConnector::from(config), server_name))
+            }
+            None => None,
+        };
+        let queue = Arc::new(EventQueue::new(self.queue_capacity, self.overflow_policy));
+        let pending = Arc::new(Pending::new());
+        let (inbound_tx, rx) = mpsc::unbounded_channel();
+        let (state_tx, state_rx) = watch::channel(ClientState::Connected);
+
+        let connector = Connector {
+            target,
+            #[cfg(feature = "tls")]
+            tls,
+            codec: Arc::new(self.codec),
+            max_event_size: self.max_event_size,
+            reconnect: self.reconnect,
+            on_reconnect: self.on_reconnect,
+            credentials: self.credentials,
+            queue: Arc::clone(&queue),
+            pending: Arc::clone(&pending),
+            inbound_tx,
+            state_tx,
+        };
+        let stream = connector.connect().await?;
+        tokio::spawn(connector.run(stream));
+
+        Ok(EventClient {
+            queue,
+            pending,
+            rx,
+            state_rx,
+        })
+    }
+}
+
+impl<T> EventClient<T> {
+    pub fn builder() -> ClientBuilder<T> {
+        ClientBuilder {
+            codec: DefaultCodec::default(),
+            max_event_size: 1024,
+            queue_capacity: 1024,
+            overflow_policy: OverflowPolicy::DropOldest,
+            reconnect: None,
+            on_reconnect: Arc::new(|_| {}),
+            credentials: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            _m: PhantomData,
+        }
+    }
+
+    /// Connects with the default configuration, same as `EventServer::builder()`
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self>
+        where
+            T: Send + 'static,
+            DefaultCodec: Codec<T>
+    {
+        Self::builder().connect(addr).await
+    }
+
+    /// Queues the event to be written applying the overflow policy, never waits
+    pub fn send(&self, event: T) -> Result<(), SendError<T>> {
+        self.queue.push(Outbound::Event(event)).map_err(|e| e.map(Outbound::into_event))
+    }
+
+    /// Same as `send`, but with the `Block` policy waits for room in the queue instead of failing
+    pub async fn send_async(&self, event: T) -> Result<(), SendError<T>> {
+        self.queue.push_wait(Outbound::Event(event)).await.map_err(|e| e.map(Outbound::into_event))
+    }
+
+    /// Sends the event to the server's `on_request` and waits up to `timeout` for what it returns.
+    /// Several requests can be in flight at once, a request caught in a reconnect may only time out
+    pub async fn request(&self, event: T, timeout: Duration) -> Result<T, RequestError> {
+        let (id, response) = self.pending.register();
+        let result = tokio::time::timeout(timeout, async {
+            match self.queue.push_wait(Outbound::Request(id, event)).await {
+                Ok(()) => response.await.map_err(|_| RequestError::Closed)?.map_err(RequestError::Failed),
+                Err(SendError::Closed(_)) => Err(RequestError::Closed),
+                Err(SendError::Full(_)) => Err(RequestError::Full),
+            }
+        }).await;
+        let result = result.unwrap_or(Err(RequestError::TimedOut));
+        if result.is_err() {
+            self.pending.cancel(id);
+        }
+        result
+    }
+
+    /// Next event sent by the server, `None` once the client is disconnected and every received event was returned
+    pub async fn recv(&mut self) -> Option<T> {
+        self.rx.recv().await
+    }
+
+    pub fn state(&self) -> ClientState {
+        *self.state_rx.borrow()
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.state() == ClientState::Disconnected
+    }
+
+    /// Resolves once the client is disconnected for good
+    pub async fn disconnected(&self) {
+        let _ = self.state_rx.clone().wait_for(|state| *state == ClientState::Disconnected).await;
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
+}
+
+impl<T> Drop for EventClient<T> {
+    fn drop(&mut self) {
+        //what is already queued is still written, then the connection closes
+        self.queue.close();
+    }
+}
+
+/// What the client connects, and reconnects, to
+enum Target {
+    Tcp(Vec<SocketAddr>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Memory(MemoryConnector),
+}
+
+/// Owns the connection, restarting it after the link dropped if reconnection is enabled
+struct Connector<T, C> {
+    target: Target,
+    #[cfg(feature = "tls")]
+    tls: Option<(tokio_rustls::TlsConnector, tokio_rustls::rustls::pki_types::ServerName<'static>)>,
+    codec: Arc<C>,
+    max_event_size: usize,
+    reconnect: Option<Reconnect>,
+    on_reconnect: Arc<dyn Fn(u32) + Send + Sync>,
+    credentials: Option<Vec<u8>>,
+    queue: Arc<EventQueue<Outbound<T>>>,
+    pending: Arc<Pending<T>>,
+    inbound_tx: mpsc::UnboundedSender<T>,
+    state_tx: watch::Sender<ClientState>,
+}
+
+impl<T, C> Connector<T, C>
+    where
+        T: Send + 'static,
+        C: Codec<T>
+{
+    async fn connect(&self) -> io::Result<BoxedStream> {
+        let mut stream = match &self.target {
+            Target::Tcp(addrs) => self.secure(TcpStream::connect(&addrs[..]).await?).await?,
+            #[cfg(unix)]
+            Target::Unix(path) => self.secure(tokio::net::UnixStream::connect(path).await?).await?,
+            Target::Memory(connector) => self.secure(connector.connect()?).await?,
+        };
+        if let Some(credentials) = &self.credentials {
+            authenticate(&mut stream, credentials, self.max_event_size).await?;
+        }
+        Ok(stream)
+    }
+
+    /// Wraps the stream in TLS if enabled
+    async fn secure<S: Stream + 'static>(&self, stream: S) -> io::Result<BoxedStream> {
+        #[cfg(feature = "tls")]
+        if let Some((connector, server_name)) = &self.tls {
+            return Ok(Box::new(connector.connect(server_name.clone(), stream).await?));
+        }
+        Ok(Box::new(stream))
+    }
+
+    async fn run(self, mut stream: BoxedStream) {
+        loop {
+            self.session(stream).await;
+            //closed by the client itself
+            if self.queue.is_closed() {
+                break;
+            }
+            let Some(reconnect) = &self.reconnect else { break };
+
+            self.state_tx.send_replace(ClientState::Reconnecting);
+            let Some((reconnected, attempts)) = self.reconnect(reconnect).await else { break };
+            stream = reconnected;
+            self.state_tx.send_replace(ClientState::Connected);
+            (self.on_reconnect)(attempts);
+        }
+        self.queue.close();
+        self.pending.clear();
+        self.state_tx.send_replace(ClientState::Disconnected);
+    }
+
+    async fn reconnect(&self, reconnect: &Reconnect) -> Option<(BoxedStream, u32)> {
+        let mut attempt = 0;
+        while reconnect.max_attempts.is_none_or(|max| attempt < max) {
+            tokio::time::sleep(reconnect.delay(attempt)).await;
+            if self.queue.is_closed() {
+                return None;
+            }
+            attempt += 1;
+            match self.connect().await {
+                Ok(stream) => return Some((stream, attempt)),
+                Err(e) => eprintln!("failed to reconnect; err = {:?}", e),
+            }
+        }
+        None
+    }
+
+    /// Runs until the link drops, or the client is dropped and its queue was flushed
+    async fn session(&self, stream: BoxedStream) {
+        let (read_half, mut write_half) = tokio::io::split(stream);
+        //outlives the writer so an event it was writing when the link dropped is requeued, not lost
+        let mut in_flight = None;
+        let pong = Notify::new();
+        let writer = async {
+            loop {
+                let written = tokio::select! {
+                    biased;
+                    _ = pong.notified() => write_frame(&mut write_half, FrameKind::Pong, &[]).await,
+                    outbound = self.queue.pop() => {
+                        let Some(outbound) = outbound else { return };
+                        let encoded = match in_flight.insert(outbound) {
+                            Outbound::Event(event) => encode_event(self.codec.as_ref(), &*event, self.max_event_size)
+                                .map(|payload| (FrameKind::Event, payload)),
+                            Outbound::Request(id, event) => encode_request(self.codec.as_ref(), *id, &*event, self.max_event_size)
+                                .map(|payload| (FrameKind::Request, payload))
+                                .inspect_err(|e| self.pending.resolve(*id, Err(e.to_string()))),
+                        };
+                        match encoded {
+                            Ok((kind, payload)) => write_frame(&mut write_half, kind, &payload).await,
+                            Err(e) => {
+                                eprintln!("Failed to serialize event: {}", e);
+                                in_flight = None;
+                                continue;
+                            }
+                        }
+                    }
+                };
+                if let Err(e) = written {
+                    eprintln!("failed to write to socket; err = {:?}", e);
+                    return;
+                }
+                in_flight = None;
+            }
+        };
+
+        tokio::select! {
+            _ = read_events(read_half, &self.inbound_tx, &self.pending, &pong, self.codec.as_ref(), self.max_event_size) => {}
+            _ = writer => {}
+        }
+        if let Some(event) = in_flight {
+            self.queue.requeue(event);
+        }
+    }
+}
+
+/// Sends the credentials and waits for the server to accept them
+async fn authenticate(stream: &mut BoxedStream, credentials: &[u8], max_event_size: usize) -> io::Result<()> {
+    write_frame(stream, FrameKind::Auth, credentials).await?;
+    let mut buf = Vec::new();
+    match read_frame(stream, &mut buf, max_event_size).await? {
+        Some(FrameKind::AuthAccepted) => Ok(()),
+        Some(FrameKind::AuthRejected) => Err(io::Error::new(io::ErrorKind::PermissionDenied, String::from_utf8_lossy(&buf).into_owned())),
+        Some(kind) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected an authentication reply, got a {kind:?} frame"))),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// Hands events and responses to the client and answers the server pings
+async fn read_events<T, C: Codec<T>, R: AsyncRead + Unpin>(read_half: R, tx: &mpsc::UnboundedSender<T>, pending: &Pending<T>, pong: &Notify, codec: &C, max_event_size: usize) {
+    let mut reader = BufReader::new(read_half);
+    let mut buf = Vec::with_capacity(max_event_size);
+    loop {
+        match read_frame(&mut reader, &mut buf, max_event_size).await {
+            Ok(Some(FrameKind::Event)) => {
+                match codec.decode(&buf) {
+                    //the client may be gone already, keep reading while its queue is flushed
+                    Ok(event) => { let _ = tx.send(event); }
+                    Err(e) => eprintln!("Failed to deserialize event: {}", e)
+                }
+            }
+            Ok(Some(FrameKind::Response)) => {
+                match decode_response(codec, &buf) {
+                    Ok((id, response)) => pending.resolve(id, response),
+                    Err(e) => eprintln!("Failed to deserialize response: {}", e)
+                }
+            }
+            Ok(Some(FrameKind::Ping)) => pong.notify_one(),
+            Ok(Some(FrameKind::Pong)) => {}
+            Ok(Some(FrameKind::Request)) => eprintln!("the server sent a request, only clients do"),
+            Ok(Some(kind @ (FrameKind::Auth | FrameKind::AuthAccepted | FrameKind::AuthRejected))) => {
+                eprintln!("unexpected {kind:?} frame after connecting")
+            }
+            // socket closed
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("failed to read from socket; err = {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "bincode"))]
+mod tests {
+    use super::*;
+    use crate::event_server::EventServer;
+    use crate::test_util::{assert_round_trip, welcome_server, Events};
+
+    #[tokio::test]
+    async fn test_round_trip_and_disconnect() {
+        let (server, mut probe) = welcome_server();
+        let server = server.run("127.0.0.1:0").await.unwrap();
+
+        let mut client = EventClient::<Events>::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+        let conn_id = assert_round_trip(&mut client, &mut probe, 7).await;
+
+        server.send_to(conn_id, Events::Value(8)).unwrap();
+        assert_eq!(client.recv().await, Some(Events::Value(8)));
+
+        server.disconnect(conn_id);
+        client.disconnected().await;
+        assert!(client.is_disconnected());
+        assert_eq!(client.recv().await, None);
+        assert!(client.send(Events::Value(9)).is_err());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_requests() {
+        let (listener, connector) = crate::listener::memory(1024);
+        let server = EventServer::<Events>::builder()
+            .request_timeout(Duration::from_millis(100))
+            .on_request(|_, event| async move {
+                let Events::Value(value) = event else { return Events::Welcome };
+                //later requests finish first, their responses still find the right caller
+                tokio::time::sleep(Duration::from_millis(50 - value as u64 * 10)).await;
+                if value == 0 {
+                    std::future::pending::<()>().await;
+                }
+                Events::Value(value * 2)
+            })
+            .serve(listener)
+            .await
+            .unwrap();
+
+        let client = EventClient::<Events>::builder().connect_memory(&connector).await.unwrap();
+        let timeout = Duration::from_secs(5);
+        let (first, second, third) = tokio::join!(
+            client.request(Events::Value(1), timeout),
+            client.request(Events::Value(2), timeout),
+            client.request(Events::Welcome, timeout),
+        );
+        assert_eq!((first, second, third), (Ok(Events::Value(2)), Ok(Events::Value(4)), Ok(Events::Welcome)));
+
+        //the server gives up first, then the client
+        assert_eq!(client.request(Events::Value(0), timeout).await, Err(RequestError::Failed("request timed out".into())));
+        assert_eq!(client.request(Events::Value(0), Duration::from_millis(10)).await, Err(RequestError::TimedOut));
+        server.shutdown().await;
+        client.disconnected().await;
+        assert_eq!(client.request(Events::Value(1), timeout).await, Err(RequestError::Closed));
+
+        let server = EventServer::<Events>::builder().run("127.0.0.1:0").await.unwrap();
+        let client = EventClient::<Events>::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+        assert_eq!(client.request(Events::Value(1), timeout).await, Err(RequestError::Failed("no request handler".into())));
+        server.shutdown().await;
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let reconnect = Reconnect {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+            max_attempts: None,
+        };
+        for (attempt, max) in [(0, 100), (1, 200), (3, 800), (4, 1000), (40, 1000)] {
+            let delay = reconnect.delay(attempt);
+            assert!(delay >= Duration::from_millis(max / 2) && delay <= Duration::from_millis(max), "{attempt} {delay:?}");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Queue capacity must be at least 1")]
+    fn test_zero_queue_capacity() {
+        let _ = EventClient::<Events>::builder().queue_capacity(0);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_keeps_queued_events() {
+        let server = EventServer::<Events>::builder().run("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().as_tcp().unwrap();
+
+        let (reconnected_tx, mut reconnected_rx) = mpsc::unbounded_channel();
+        let client = EventClient::<Events>::builder()
+            .reconnect(Reconnect {
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(50),
+                max_attempts: None,
+            })
+            .on_reconnect(move |attempts| reconnected_tx.send(attempts).unwrap())
+            .connect(address)
+            .await
+            .unwrap();
+        while server.connection_ids().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        server.shutdown().await;
+        while client.state() != ClientState::Reconnecting {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        for i in 0..3 {
+            client.send(Events::Value(i)).unwrap();
+        }
+        assert_eq!(client.queue_stats().len, 3);
+
+        let (received_tx, mut received_rx) = mpsc::unbounded_channel();
+        let server = EventServer::<Events>::builder()
+            .on_event(move |_, event| received_tx.send(event).unwrap())
+            .run(address)
+            .await
+            .unwrap();
+
+        assert!(reconnected_rx.recv().await.unwrap() >= 1);
+        for i in 0..3 {
+            assert_eq!(received_rx.recv().await, Some(Events::Value(i)));
+        }
+        assert_eq!(client.state(), ClientState::Connected);
+        assert_eq!(client.queue_stats().dropped, 0);
+
+        server.shutdown().await;
+    }
+}
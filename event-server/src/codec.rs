@@ -0,0 +1,199 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+
+#[cfg(not(any(feature = "bincode", feature = "json", feature = "msgpack")))]
+compile_error!("enable at least one codec feature: bincode, json or msgpack");
+
+/// Turns events into frame payloads and back, both ends of a connection have to use the same codec
+pub trait Codec<T>: Send + Sync + 'static {
+    /// Appends the encoded event to `buf`
+    fn encode(&self, event: &T, buf: &mut Vec<u8>) -> Result<(), CodecError>;
+
+    fn decode(&self, frame: &[u8]) -> Result<T, CodecError>;
+}
+
+/// Bincode when enabled, otherwise JSON, otherwise MessagePack
+#[cfg(feature = "bincode")]
+pub type DefaultCodec = BincodeCodec;
+#[cfg(all(not(feature = "bincode"), feature = "json"))]
+pub type DefaultCodec = JsonCodec;
+#[cfg(all(not(feature = "bincode"), not(feature = "json"), feature = "msgpack"))]
+pub type DefaultCodec = MsgPackCodec;
+
+pub struct CodecError(Box<dyn Error + Send + Sync>);
+
+impl CodecError {
+    pub fn new(error: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        CodecError(error.into())
+    }
+}
+
+impl Debug for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl Error for CodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
+
+/// Encodes and checks the size against the frame limit
+pub(crate) fn encode_event<T, C: Codec<T>>(codec: &C, event: &T, max_event_size: usize) -> Result<Vec<u8>, CodecError> {
+    let mut payload = Vec::new();
+    codec.encode(event, &mut payload)?;
+    check_size(payload, max_event_size)
+}
+
+/// Rejects payloads the peer would refuse to read
+pub(crate) fn check_size(payload: Vec<u8>, max_event_size: usize) -> Result<Vec<u8>, CodecError> {
+    if payload.len() > max_event_size {
+        return Err(CodecError::new(format!("event of {} bytes exceeds max size of {}", payload.len(), max_event_size)));
+    }
+    Ok(payload)
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy)]
+pub struct BincodeCodec {
+    config: bincode::config::Configuration,
+}
+
+#[cfg(feature = "bincode")]
+impl BincodeCodec {
+    pub fn new(config: bincode::config::Configuration) -> Self {
+        Self { config }
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl Default for BincodeCodec {
+    fn default() -> Self {
+        Self::new(bincode::config::standard())
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<T: bincode::Encode + bincode::Decode<()>> Codec<T> for BincodeCodec {
+    fn encode(&self, event: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        bincode::encode_into_std_write(event, buf, self.config).map_err(CodecError::new)?;
+        Ok(())
+    }
+
+    fn decode(&self, frame: &[u8]) -> Result<T, CodecError> {
+        let (event, _) = bincode::decode_from_slice(frame, self.config).map_err(CodecError::new)?;
+        Ok(event)
+    }
+}
+
+/// Events as JSON text, for web clients
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for JsonCodec {
+    fn encode(&self, event: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        serde_json::to_writer(buf, event).map_err(CodecError::new)
+    }
+
+    fn decode(&self, frame: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(frame).map_err(CodecError::new)
+    }
+}
+
+/// Events as MessagePack with named fields, so other languages can map them without knowing the field order
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> Codec<T> for MsgPackCodec {
+    fn encode(&self, event: &T, buf: &mut Vec<u8>) -> Result<(), CodecError> {
+        rmp_serde::encode::write_named(buf, event).map_err(CodecError::new)
+    }
+
+    fn decode(&self, frame: &[u8]) -> Result<T, CodecError> {
+        rmp_serde::from_slice(frame).map_err(CodecError::new)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "json", feature = "msgpack"))]
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    enum Events {
+        Joined { name: String },
+        Value(u32),
+    }
+
+    #[cfg(any(feature = "json", feature = "msgpack"))]
+    fn round_trip<C: Codec<Events>>(codec: C) {
+        let mut buf = vec![];
+        for event in [Events::Joined { name: "a".into() }, Events::Value(3)] {
+            buf.clear();
+            codec.encode(&event, &mut buf).unwrap();
+            assert_eq!(codec.decode(&buf).unwrap(), event);
+        }
+        assert!(codec.decode(&[0xff, 0x00]).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        round_trip(JsonCodec);
+        let mut buf = vec![];
+        JsonCodec.encode(&Events::Value(3), &mut buf).unwrap();
+        assert_eq!(buf, br#"{"Value":3}"#);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_json_server_and_client() {
+        let (received_tx, mut received_rx) = tokio::sync::mpsc::unbounded_channel();
+        let server = crate::EventServer::<Events>::builder()
+            .codec(JsonCodec)
+            .on_connect(|conn| {
+                conn.send(Events::Joined { name: "server".into() }).unwrap();
+            })
+            .on_event(move |_, event| received_tx.send(event).unwrap())
+            .run("127.0.0.1:0")
+            .await
+            .unwrap();
+
+        let mut client = crate::EventClient::<Events>::builder()
+            .codec(JsonCodec)
+            .connect(server.local_addr().as_tcp().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(client.recv().await, Some(Events::Joined { name: "server".into() }));
+        client.send(Events::Value(7)).unwrap();
+        assert_eq!(received_rx.recv().await, Some(Events::Value(7)));
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack() {
+        round_trip(MsgPackCodec);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn test_size_limit() {
+        let event = vec![0u8; 16];
+        assert!(encode_event(&BincodeCodec::default(), &event, 17).is_ok());
+        assert!(encode_event(&BincodeCodec::default(), &event, 16).is_err());
+    }
+}
@@ -1,30 +1,300 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+#[cfg(feature = "websocket")]
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
-use bincode::{Decode, Encode};
-use bincode::config::Configuration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
 use tokio::net::{TcpListener, ToSocketAddrs};
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
+use tokio::time::{Instant, Interval};
 
-pub struct EventServer<T: Decode + Encode> {
-    config: Configuration,
+use crate::codec::{encode_event, Codec, DefaultCodec};
+use crate::frame::{FrameKind, FrameReader, FrameWriter};
+use crate::listener::{Listener, PeerAddr};
+use crate::queue::{EventQueue, OverflowPolicy, QueueStats, SendError};
+use crate::limit::{ConnectionLimits, RateLimit, RateLimitAction, TokenBucket};
+use crate::rpc::{decode_request, encode_response, Response};
+
+/// Clients that haven't finished the TLS or WebSocket handshake by then are dropped
+#[cfg(any(feature = "tls", feature = "websocket"))]
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `S` is the state `on_connect` creates for each connection, `H` tracks whether `on_event_async` fixed it already
+pub struct EventServer<T, C = DefaultCodec, S = (), H = OpenState> {
+    codec: Arc<C>,
     max_event_size: usize,
+    shutdown_timeout: Duration,
+    heartbeat_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    queue_capacity: usize,
+    overflow_policy: OverflowPolicy,
+    connections: Arc<Connections<T>>,
+    on_connect: ConnectCallback<T, S>,
+    on_disconnect: ConnectionCallback<T>,
+    on_event: EventHandler<T, S>,
+    on_request: Option<RequestHandler<T>>,
+    request_timeout: Duration,
+    authenticate: Option<Authenticator>,
+    auth_timeout: Duration,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    rate_limit: Option<RateLimit>,
+    #[cfg(feature = "tls")]
+    tls: Option<tokio_rustls::TlsAcceptor>,
+    #[cfg(feature = "websocket")]
+    websocket: Option<SocketAddr>,
+    handler: PhantomData<fn() -> H>,
+}
+
+/// Builder without an `on_event_async` handler yet, `on_connect` can still change the state type
+pub struct OpenState;
+
+/// Builder with an `on_event_async` handler, the state type it takes can't change anymore
+pub struct FixedState;
+
+//every connection task gets its own copy of the settings and callbacks
+impl<T, C, S, H> Clone for EventServer<T, C, S, H> {
+    fn clone(&self) -> Self {
+        Self {
+            codec: Arc::clone(&self.codec),
+            max_event_size: self.max_event_size,
+            shutdown_timeout: self.shutdown_timeout,
+            heartbeat_interval: self.heartbeat_interval,
+            idle_timeout: self.idle_timeout,
+            queue_capacity: self.queue_capacity,
+            overflow_policy: self.overflow_policy,
+            connections: Arc::clone(&self.connections),
+            on_connect: Arc::clone(&self.on_connect),
+            on_disconnect: Arc::clone(&self.on_disconnect),
+            on_event: self.on_event.clone(),
+            on_request: self.on_request.clone(),
+            request_timeout: self.request_timeout,
+            authenticate: self.authenticate.clone(),
+            auth_timeout: self.auth_timeout,
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+            rate_limit: self.rate_limit,
+            #[cfg(feature = "tls")]
+            tls: self.tls.clone(),
+            #[cfg(feature = "websocket")]
+            websocket: self.websocket,
+            handler: PhantomData,
+        }
+    }
+}
+
+type ConnectionCallback<T> = Arc<dyn Fn(&Connection<T>) + Send + Sync>;
+
+type ConnectCallback<T, S> = Arc<dyn Fn(&Connection<T>) -> S + Send + Sync>;
+
+/// What `on_event_async` handlers return, `Box::pin(async move { .. })`
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+type RequestHandler<T> = Arc<dyn Fn(usize, T) -> BoxFuture<'static, T> + Send + Sync>;
+
+/// Identity of the client on success, the reason it is rejected otherwise
+type Authenticator = Arc<dyn Fn(PeerAddr, Vec<u8>) -> BoxFuture<'static, Result<String, String>> + Send + Sync>;
+
+type AsyncEventHandler<T, S> = Arc<dyn for<'a> Fn(&'a mut Context<T, S>, T) -> BoxFuture<'a, ()> + Send + Sync>;
+
+enum EventHandler<T, S> {
+    Sync(Arc<dyn Fn(usize, T) + Send + Sync>),
+    Async(AsyncEventHandler<T, S>),
+}
+
+impl<T, S> Clone for EventHandler<T, S> {
+    fn clone(&self) -> Self {
+        match self {
+            EventHandler::Sync(handler) => EventHandler::Sync(Arc::clone(handler)),
+            EventHandler::Async(handler) => EventHandler::Async(Arc::clone(handler)),
+        }
+    }
+}
+
+/// Handle to a running server, cheap to clone so it can be handed to game logic running elsewhere.
+/// Dropping every handle leaves the server running
+pub struct ServerHandle<T> {
+    local_addr: PeerAddr,
+    #[cfg(feature = "websocket")]
+    websocket_addr: Option<SocketAddr>,
     connections: Arc<Connections<T>>,
-    on_connect: Arc<dyn Fn(&Connection<T>) + Send + Sync>,
-    on_disconnect: Arc<dyn Fn(&Connection<T>) + Send + Sync>,
-    on_event: Arc<dyn Fn(usize, T) + Send + Sync>,
+    shutdown_tx: Arc<watch::Sender<bool>>,
+    stopped_rx: watch::Receiver<bool>,
 }
 
-type Tx<T> = mpsc::UnboundedSender<T>;
-type Rx<T> = mpsc::UnboundedReceiver<T>;
+impl<T> Clone for ServerHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            local_addr: self.local_addr.clone(),
+            #[cfg(feature = "websocket")]
+            websocket_addr: self.websocket_addr,
+            connections: Arc::clone(&self.connections),
+            shutdown_tx: Arc::clone(&self.shutdown_tx),
+            stopped_rx: self.stopped_rx.clone(),
+        }
+    }
+}
+
+impl<T> ServerHandle<T> {
+    pub fn local_addr(&self) -> &PeerAddr {
+        &self.local_addr
+    }
+
+    /// Address of the WebSocket listener, `None` if the server wasn't built with one
+    #[cfg(feature = "websocket")]
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.websocket_addr
+    }
+
+    pub fn send_to(&self, conn_id: usize, event: T) -> Result<(), SendError<T>> {
+        self.connections.send_to(conn_id, event)
+    }
+
+    /// Same as `send_to`, but with the `Block` policy waits for room in the connection queue instead of failing
+    pub async fn send_to_async(&self, conn_id: usize, event: T) -> Result<(), SendError<T>> {
+        let (queue, close) = match self.connections.peers.read().unwrap().get(&conn_id) {
+            Some(conn) => (Arc::clone(&conn.queue), Arc::clone(&conn.close)),
+            None => return Err(SendError::Closed(event)),
+        };
+        let result = queue.push_wait(Outbound::Event(event)).await;
+        disconnect_on_overflow(result, &queue, &close).map_err(|e| e.map(Outbound::into_event))
+    }
+
+    /// Queues the event for every connection, connections with a full queue may miss it depending on the policy
+    pub fn broadcast(&self, event: T)
+        where
+            T: Clone
+    {
+        self.connections.broadcast(None, event)
+    }
+
+    /// Queues the event for every connection but `conn_id`, usually the one it came from
+    pub fn broadcast_except(&self, conn_id: usize, event: T)
+        where
+            T: Clone
+    {
+        self.connections.broadcast(Some(conn_id), event)
+    }
+
+    /// Adds the connection to `room`, creating the room if needed. Returns `false` if there is no such connection.
+    /// Connections leave every room when they disconnect
+    pub fn join(&self, conn_id: usize, room: impl Into<String>) -> bool {
+        self.connections.join(conn_id, room.into())
+    }
+
+    /// Removes the connection from `room`, which is gone once empty. Returns `false` if it wasn't a member
+    pub fn leave(&self, conn_id: usize, room: &str) -> bool {
+        self.connections.leave(conn_id, room)
+    }
+
+    /// Queues the event for every member of `room`, same as `broadcast` otherwise
+    pub fn publish(&self, room: &str, event: T)
+        where
+            T: Clone
+    {
+        self.connections.publish(room, None, event)
+    }
+
+    /// Queues the event for every member of `room` but `conn_id`, usually the one it came from
+    pub fn publish_except(&self, room: &str, conn_id: usize, event: T)
+        where
+            T: Clone
+    {
+        self.connections.publish(room, Some(conn_id), event)
+    }
+
+    /// Ids of the members of `room` in ascending order, empty if there is no such room
+    pub fn members(&self, room: &str) -> Vec<usize> {
+        let mut ids: Vec<_> = self.connections.rooms.read().unwrap().get(room).into_iter().flatten().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Rooms the connection is a member of, sorted by name
+    pub fn rooms_of(&self, conn_id: usize) -> Vec<String> {
+        let mut rooms: Vec<_> = self.connections.rooms.read().unwrap().iter()
+            .filter(|(_, members)| members.contains(&conn_id))
+            .map(|(room, _)| room.clone())
+            .collect();
+        rooms.sort_unstable();
+        rooms
+    }
+
+    /// Rooms with at least one member, sorted by name
+    pub fn rooms(&self) -> Vec<String> {
+        let mut rooms: Vec<_> = self.connections.rooms.read().unwrap().keys().cloned().collect();
+        rooms.sort_unstable();
+        rooms
+    }
+
+    /// Ids of the open connections in ascending order
+    pub fn connection_ids(&self) -> Vec<usize> {
+        let mut ids: Vec<_> = self.connections.peers.read().unwrap().keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Queue stats of the connection, `None` if there is no such connection
+    pub fn queue_stats(&self, conn_id: usize) -> Option<QueueStats> {
+        self.connections.peers.read().unwrap().get(&conn_id).map(Connection::queue_stats)
+    }
+
+    /// Closes the connection after flushing what is queued for it, returns `false` if there is no such connection
+    pub fn disconnect(&self, conn_id: usize) -> bool {
+        match self.connections.peers.read().unwrap().get(&conn_id) {
+            Some(conn) => {
+                conn.close.notify_one();
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Stops accepting, flushes what is queued for each connection and closes it, firing `on_disconnect`.
+    /// Resolves once every connection is closed
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(true);
+        //only errors if the server task panicked, in which case it is stopped as well
+        let _ = self.stopped_rx.clone().wait_for(|stopped| *stopped).await;
+    }
+}
+
+/// What a listener speaks on top of TCP, or TLS if enabled
+#[derive(Clone, Copy)]
+enum Transport {
+    /// The length prefixed frames of [`crate::frame`]
+    Raw,
+    #[cfg(feature = "websocket")]
+    WebSocket,
+}
+
+/// Resolves on the next tick, never if there is no interval
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => { interval.tick().await; }
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves once the flag is set, never if its sender was dropped without setting it
+async fn signalled(rx: &mut watch::Receiver<bool>) {
+    if rx.wait_for(|set| *set).await.is_err() {
+        std::future::pending::<()>().await
+    }
+}
 
 struct Connections<T> {
     counter: AtomicUsize,
     peers: RwLock<HashMap<usize, Connection<T>>>,
+    //always locked after `peers` when both are needed
+    rooms: RwLock<HashMap<String, HashSet<usize>>>,
 }
 
 impl<T> Connections<T> {
@@ -32,86 +302,372 @@ impl<T> Connections<T> {
         Self {
             counter: AtomicUsize::new(0),
             peers: Default::default(),
+            rooms: Default::default(),
         }
     }
 
-    pub fn insert(&self, address: SocketAddr, tx: Tx<T>) -> usize {
+    pub fn insert(&self, address: PeerAddr, identity: Option<Arc<str>>, queue: Arc<EventQueue<Outbound<T>>>, close: Arc<Notify>) -> Connection<T> {
         let mut lock = self.peers.write().unwrap();
         let id = self.counter.fetch_add(1, Ordering::Relaxed);
-        lock.insert(id, Connection {
+        let conn = Connection {
             id,
-            tx,
+            queue,
             address,
-        });
-        return id;
+            identity,
+            close,
+        };
+        lock.insert(id, conn.clone());
+        conn
     }
 
     pub fn remove(&self, conn_id: usize) -> Option<Connection<T>> {
-        let mut lock = self.peers.write().unwrap();
-        return lock.remove(&conn_id);
+        let conn = self.peers.write().unwrap().remove(&conn_id);
+        //once out of `peers` it can't join again, so nothing is left behind
+        self.rooms.write().unwrap().retain(|_, members| {
+            members.remove(&conn_id);
+            !members.is_empty()
+        });
+        conn
+    }
+
+    pub fn join(&self, conn_id: usize, room: String) -> bool {
+        //held so the connection can't be removed before it is added to the room
+        let peers = self.peers.read().unwrap();
+        if !peers.contains_key(&conn_id) {
+            return false;
+        }
+        self.rooms.write().unwrap().entry(room).or_default().insert(conn_id);
+        true
+    }
+
+    pub fn leave(&self, conn_id: usize, room: &str) -> bool {
+        let mut rooms = self.rooms.write().unwrap();
+        let Some(members) = rooms.get_mut(room) else { return false };
+        let removed = members.remove(&conn_id);
+        if members.is_empty() {
+            rooms.remove(room);
+        }
+        removed
+    }
+
+    pub fn publish(&self, room: &str, except: Option<usize>, data: T)
+        where
+            T: Clone
+    {
+        let peers = self.peers.read().unwrap();
+        if let Some(members) = self.rooms.read().unwrap().get(room) {
+            for conn in members.iter().filter(|id| Some(**id) != except).filter_map(|id| peers.get(id)) {
+                //a closed queue means the connection is going away, nothing to report
+                let _ = conn.send(data.clone());
+            }
+        }
     }
 
     pub fn send_to(&self, conn_id: usize, data: T) -> Result<(), SendError<T>> {
-        if let Ok(mut lock) = self.peers.read() {
+        if let Ok(lock) = self.peers.read() {
             match lock.get(&conn_id) {
                 None => {
                     println!("No connection with id {conn_id}");
-                    return Err(SendError(data));
+                    return Err(SendError::Closed(data));
                 }
                 Some(conn) => {
                     return conn.send(data);
                 }
             }
         }
-        return Err(SendError(data));
+        Err(SendError::Closed(data))
+    }
+
+    pub fn broadcast(&self, except: Option<usize>, data: T)
+        where
+            T: Clone
+    {
+        if let Ok(lock) = self.peers.read() {
+            for conn in lock.values().filter(|conn| Some(conn.id) != except) {
+                //a closed queue means the connection is going away, nothing to report
+                let _ = conn.send(data.clone());
+            }
+        }
+    }
+}
+
+/// Responses share the queue with events so both count towards its capacity and overflow policy
+enum Outbound<T> {
+    Event(T),
+    Response(u64, Response<T>),
+}
+
+impl<T> Outbound<T> {
+    //only events are ever handed back to callers
+    fn into_event(self) -> T {
+        match self {
+            Outbound::Event(event) => event,
+            Outbound::Response(..) => unreachable!("responses are only queued by the connection itself"),
+        }
     }
 }
 
-#[derive(Debug)]
 pub struct Connection<T> {
     id: usize,
-    tx: Tx<T>,
-    address: SocketAddr,
+    queue: Arc<EventQueue<Outbound<T>>>,
+    address: PeerAddr,
+    identity: Option<Arc<str>>,
+    close: Arc<Notify>,
+}
+
+/// A queue that closed itself on overflow has the `Disconnect` policy, the connection has to follow
+fn disconnect_on_overflow<T>(result: Result<(), SendError<T>>, queue: &EventQueue<T>, close: &Notify) -> Result<(), SendError<T>> {
+    if matches!(result, Err(SendError::Full(_))) && queue.is_closed() {
+        close.notify_one();
+    }
+    result
+}
+
+//a handle to the same connection, which stays open as long as the server keeps it
+impl<T> Clone for Connection<T> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            queue: Arc::clone(&self.queue),
+            address: self.address.clone(),
+            identity: self.identity.clone(),
+            close: Arc::clone(&self.close),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Connection<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("id", &self.id)
+            .field("address", &self.address)
+            .field("identity", &self.identity)
+            .field("queue", &self.queue.stats())
+            .finish()
+    }
 }
 
 impl<T> Connection<T> {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn address(&self) -> &PeerAddr {
+        &self.address
+    }
+
+    /// What `authenticate` accepted the client as, `None` if the server doesn't authenticate
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    /// Queues the event applying the overflow policy, never waits
     pub fn send(&self, data: T) -> Result<(), SendError<T>> {
-        return self.tx.send(data);
+        disconnect_on_overflow(self.queue.push(Outbound::Event(data)), &self.queue, &self.close)
+            .map_err(|e| e.map(Outbound::into_event))
+    }
+
+    /// Same as `send`, but with the `Block` policy waits for room in the queue instead of failing
+    pub async fn send_async(&self, data: T) -> Result<(), SendError<T>> {
+        let result = self.queue.push_wait(Outbound::Event(data)).await;
+        disconnect_on_overflow(result, &self.queue, &self.close).map_err(|e| e.map(Outbound::into_event))
+    }
+
+    /// Queues the response to a request like an event, a response the policy drops leaves the client to time out
+    async fn respond(&self, id: u64, response: Response<T>) {
+        let result = self.queue.push_wait(Outbound::Response(id, response)).await;
+        let _ = disconnect_on_overflow(result, &self.queue, &self.close);
+    }
+
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
     }
 }
 
+/// What `on_event_async` handlers get along with each event, the connection and the state `on_connect` created for it
+pub struct Context<T, S> {
+    conn: Connection<T>,
+    state: S,
+}
 
-impl<T> EventServer<T>
-    where
-        T: Decode + Encode + Send + 'static
-{
+impl<T, S> Context<T, S> {
+    pub fn connection(&self) -> &Connection<T> {
+        &self.conn
+    }
+
+    pub fn id(&self) -> usize {
+        self.conn.id
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut S {
+        &mut self.state
+    }
+
+    /// Queues an event for the connection the event came from, see [`Connection::send`]
+    pub fn reply(&self, event: T) -> Result<(), SendError<T>> {
+        self.conn.send(event)
+    }
+
+    /// See [`Connection::send_async`]
+    pub async fn reply_async(&self, event: T) -> Result<(), SendError<T>> {
+        self.conn.send_async(event).await
+    }
+}
+
+
+impl<T, C: Default> EventServer<T, C> {
     pub fn builder() -> Self {
         Self {
             max_event_size: 1024,
-            config: bincode::config::standard(),
+            codec: Arc::new(C::default()),
+            shutdown_timeout: Duration::from_secs(5),
+            heartbeat_interval: None,
+            idle_timeout: None,
+            queue_capacity: 1024,
+            overflow_policy: OverflowPolicy::DropOldest,
             connections: Arc::new(Connections::new()),
             on_connect: Arc::new(|_| {}),
             on_disconnect: Arc::new(|_| {}),
-            on_event: Arc::new(|_, _| {}),
+            on_event: EventHandler::Sync(Arc::new(|_, _| {})),
+            on_request: None,
+            request_timeout: Duration::from_secs(30),
+            authenticate: None,
+            auth_timeout: Duration::from_secs(10),
+            max_connections: None,
+            max_connections_per_ip: None,
+            rate_limit: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "websocket")]
+            websocket: None,
+            handler: PhantomData,
         }
     }
+}
 
-    pub fn bincode_config<F>(mut self, config: Configuration) -> Self {
-        self.config = config;
-        return self;
+#[cfg(feature = "bincode")]
+impl<T, S, H> EventServer<T, crate::codec::BincodeCodec, S, H>
+    where
+        T: Send + 'static,
+        crate::codec::BincodeCodec: Codec<T>
+{
+    pub fn bincode_config(self, config: bincode::config::Configuration) -> Self {
+        self.codec(crate::codec::BincodeCodec::new(config))
     }
+}
 
-    pub fn max_event_size<F>(mut self, max_event_size: usize) -> Self {
-        self.max_event_size = max_event_size;
-        return self;
+impl<T, C, S, H> EventServer<T, C, S, H> {
+    /// Encodes events with `codec` instead, clients have to use the same one
+    pub fn codec<C2: Codec<T>>(self, codec: C2) -> EventServer<T, C2, S, H> {
+        let (on_connect, on_event) = (Arc::clone(&self.on_connect), self.on_event.clone());
+        self.rebuild(Arc::new(codec), on_connect, on_event)
+    }
+
+    fn rebuild<C2, S2, H2>(self, codec: Arc<C2>, on_connect: ConnectCallback<T, S2>, on_event: EventHandler<T, S2>) -> EventServer<T, C2, S2, H2> {
+        EventServer {
+            codec,
+            max_event_size: self.max_event_size,
+            shutdown_timeout: self.shutdown_timeout,
+            heartbeat_interval: self.heartbeat_interval,
+            idle_timeout: self.idle_timeout,
+            queue_capacity: self.queue_capacity,
+            overflow_policy: self.overflow_policy,
+            connections: self.connections,
+            on_connect,
+            on_disconnect: self.on_disconnect,
+            on_event,
+            on_request: self.on_request,
+            request_timeout: self.request_timeout,
+            authenticate: self.authenticate,
+            auth_timeout: self.auth_timeout,
+            max_connections: self.max_connections,
+            max_connections_per_ip: self.max_connections_per_ip,
+            rate_limit: self.rate_limit,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            #[cfg(feature = "websocket")]
+            websocket: self.websocket,
+            handler: PhantomData,
+        }
     }
+}
 
-    pub fn on_connect<F>(mut self, on_connect: F) -> Self
+impl<T, C, S> EventServer<T, C, S, OpenState> {
+    /// Called once a client connected, what it returns is the state of the connection handed to `on_event_async`.
+    /// Changes the state type, so it is only available until `on_event_async` is set
+    pub fn on_connect<S2, F>(self, on_connect: F) -> EventServer<T, C, S2>
         where
-            F: Fn(&Connection<T>) + 'static + Send + Sync
+            F: Fn(&Connection<T>) -> S2 + 'static + Send + Sync
     {
-        self.on_connect = Arc::new(on_connect);
-        return self;
+        //only `on_event_async` sets an async handler and it fixes the state
+        let EventHandler::Sync(on_event) = self.on_event.clone() else { unreachable!() };
+        let codec = Arc::clone(&self.codec);
+        self.rebuild(codec, Arc::new(on_connect), EventHandler::Sync(on_event))
+    }
+}
+
+impl<T, C, S, H> EventServer<T, C, S, H>
+    where
+        T: Send + 'static,
+        C: Codec<T>,
+        S: Send + 'static,
+        H: 'static
+{
+    /// Largest encoded event accepted or sent, frames announcing a bigger size close the connection
+    pub fn max_event_size(mut self, max_event_size: usize) -> Self {
+        self.max_event_size = max_event_size;
+        self
+    }
+
+    /// How long `ServerHandle::shutdown` and `ServerHandle::disconnect` wait for a connection to flush its queued events before dropping them
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Sends a ping every `interval`, clients answer with a pong so a live but quiet connection isn't idle
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Closes connections nothing was received from for `timeout`, firing `on_disconnect`.
+    /// Should be a few heartbeat intervals so a single late pong doesn't drop the connection
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Number of events waiting to be written to a connection before the overflow policy kicks in, 1024 by default, has to be at least 1
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        assert!(queue_capacity > 0, "Queue capacity must be at least 1");
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    /// What to do when a client doesn't read fast enough, `DropOldest` by default
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Wraps every accepted connection in TLS, see [`crate::tls::server_config`] to load it from PEM files
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: Arc<tokio_rustls::rustls::ServerConfig>) -> Self {
+        self.tls = Some(tokio_rustls::TlsAcceptor::from(config));
+        self
+    }
+
+    /// Also accepts WebSocket clients on `addr`, such as browsers, each binary message carrying one encoded event.
+    /// They get the same callbacks and handle as the other connections, and TLS if enabled
+    #[cfg(feature = "websocket")]
+    pub fn websocket(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.websocket = Some(addr.into());
+        self
     }
 
     pub fn on_disconnect<F>(mut self, on_disconnect: F) -> Self
@@ -119,93 +675,462 @@ impl<T> EventServer<T>
             F: Fn(&Connection<T>) + 'static + Send + Sync
     {
         self.on_disconnect = Arc::new(on_disconnect);
-        return self;
+        self
     }
 
 
+    /// Replaces `on_event_async`
     pub fn on_event<F>(mut self, on_event: F) -> Self
         where
             F: Fn(usize, T) + 'static + Send + Sync
     {
-        self.on_event = Arc::new(on_event);
-        return self;
+        self.on_event = EventHandler::Sync(Arc::new(on_event));
+        self
     }
 
-    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<(), Box<dyn std::error::Error>> {
+    /// Handles events with a future instead, replacing `on_event`. The events of a connection are handled one at a time,
+    /// the next frame is only read once the future completed, so the handler has the connection state to itself.
+    /// Fixes the state type, `on_connect` has to come first:
+    ///
+    /// ```compile_fail
+    /// # use event_server::EventServer;
+    /// EventServer::<u32>::builder()
+    ///     .on_event_async(|ctx, event| Box::pin(async move { ctx.reply(event).unwrap(); }))
+    ///     .on_connect(|conn| conn.id());
+    /// ```
+    pub fn on_event_async<F>(self, on_event: F) -> EventServer<T, C, S, FixedState>
+        where
+            F: for<'a> Fn(&'a mut Context<T, S>, T) -> BoxFuture<'a, ()> + 'static + Send + Sync
+    {
+        let (codec, on_connect) = (Arc::clone(&self.codec), Arc::clone(&self.on_connect));
+        self.rebuild(codec, on_connect, EventHandler::Async(Arc::new(on_event)))
+    }
 
-        let (shutdown_tx, shutdown_rx) = mpsc::unbounded_channel::<()>();
+    /// Answers requests sent with [`crate::EventClient::request`]. Each request runs in its own task,
+    /// so a connection can have up to `queue_capacity` in flight. Responses go through the connection's queue
+    /// like events and follow its [`OverflowPolicy`], in the order they are ready
+    pub fn on_request<F, Fut>(mut self, on_request: F) -> Self
+        where
+            F: Fn(usize, T) -> Fut + 'static + Send + Sync,
+            Fut: Future<Output = T> + Send + 'static
+    {
+        self.on_request = Some(Arc::new(move |conn_id, event| Box::pin(on_request(conn_id, event))));
+        self
+    }
+
+    /// Makes clients authenticate before they count as connected: their first frame is handed to `authenticate`
+    /// along with their address, `Ok` attaches the identity to the connection, `Err` rejects it with that reason.
+    /// Neither `on_connect` nor `on_disconnect` fire for rejected clients
+    pub fn authenticate<F, Fut>(mut self, authenticate: F) -> Self
+        where
+            F: Fn(PeerAddr, Vec<u8>) -> Fut + 'static + Send + Sync,
+            Fut: Future<Output = Result<String, String>> + Send + 'static
+    {
+        self.authenticate = Some(Arc::new(move |address, credentials| Box::pin(authenticate(address, credentials))));
+        self
+    }
+
+    /// How long clients have to send their credentials and get them checked, 10 seconds by default
+    pub fn auth_timeout(mut self, timeout: Duration) -> Self {
+        self.auth_timeout = timeout;
+        self
+    }
+
+    /// Clients connecting while this many are connected are dropped right away, before any handshake
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Same as `max_connections`, counting only the clients with the same IP as [`Connection::address`]
+    pub fn max_connections_per_ip(mut self, max_connections: usize) -> Self {
+        self.max_connections_per_ip = Some(max_connections);
+        self
+    }
+
+    /// Limits how fast each connection may send events and requests, no limit by default.
+    /// `per_second` has to be a positive number and `burst` at least 1
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        assert!(rate_limit.per_second.is_finite() && rate_limit.per_second > 0.0 && rate_limit.burst > 0, "Rate limit must allow at least some events");
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// How long `on_request` may take before the client gets an error instead, 30 seconds by default
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
 
+    /// Binds to `addr` and accepts connections in the background until the returned handle is shut down
+    pub async fn run<A: ToSocketAddrs>(self, addr: A) -> Result<ServerHandle<T>, Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(addr).await?;
+        Ok(self.serve(listener).await?)
+    }
 
+    /// Accepts connections from `listener` in the background until the returned handle is shut down,
+    /// such as a `UnixListener` or the in process [`crate::listener::memory`] transport
+    pub async fn serve<L: Listener>(self, listener: L) -> io::Result<ServerHandle<T>> {
+        let local_addr = listener.local_addr()?;
+        #[cfg(feature = "websocket")]
+        let websocket_listener = match self.websocket {
+            Some(addr) => Some(TcpListener::bind(addr).await?),
+            None => None,
+        };
+        #[cfg(feature = "websocket")]
+        let websocket_addr = websocket_listener.as_ref().map(TcpListener::local_addr).transpose()?;
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (stopped_tx, stopped_rx) = watch::channel(false);
+        let connections = Arc::clone(&self.connections);
+        let limits = ConnectionLimits::new(self.max_connections, self.max_connections_per_ip);
+        tokio::spawn(async move {
+            #[cfg(feature = "websocket")]
+            if let Some(websocket_listener) = websocket_listener {
+                tokio::join!(
+                    self.clone().accept(listener, Transport::Raw, Arc::clone(&limits), shutdown_rx.clone()),
+                    self.accept(websocket_listener, Transport::WebSocket, limits, shutdown_rx),
+                );
+                let _ = stopped_tx.send(true);
+                return;
+            }
+            self.accept(listener, Transport::Raw, limits, shutdown_rx).await;
+            let _ = stopped_tx.send(true);
+        });
+
+        Ok(ServerHandle {
+            local_addr,
+            #[cfg(feature = "websocket")]
+            websocket_addr,
+            connections,
+            shutdown_tx: Arc::new(shutdown_tx),
+            stopped_rx,
+        })
+    }
+
+    async fn accept<L: Listener>(self, mut listener: L, transport: Transport, limits: Arc<ConnectionLimits>, mut shutdown_rx: watch::Receiver<bool>) {
+        let mut tasks = JoinSet::new();
         loop {
-            let (mut stream, address) = listener.accept().await?;
+            tokio::select! {
+                _ = signalled(&mut shutdown_rx) => break,
+                //reap finished connections so the set doesn't grow with every client ever seen
+                Some(_) = tasks.join_next() => {}
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, address)) => match limits.acquire(&address) {
+                        Some(permit) => {
+                            let connection = self.clone().handshake(stream, address, transport, shutdown_rx.clone());
+                            tasks.spawn(async move {
+                                connection.await;
+                                drop(permit);
+                            });
+                        }
+                        None => eprintln!("refused {address}, too many connections"),
+                    },
+                    Err(e) => eprintln!("failed to accept connection; err = {:?}", e),
+                }
+            }
+        }
 
+        drop(listener);
+        while tasks.join_next().await.is_some() {}
+    }
 
-            let on_connect = Arc::clone(&self.on_connect);
-            let on_event = Arc::clone(&self.on_event);
-            let on_disconnect = Arc::clone(&self.on_disconnect);
+    /// Runs the TLS handshake if enabled, off the accept loop so a slow client doesn't hold up the others
+    async fn handshake<IO>(self, stream: IO, address: PeerAddr, transport: Transport, shutdown_rx: watch::Receiver<bool>)
+        where
+            IO: AsyncRead + AsyncWrite + Send + Unpin + 'static
+    {
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = self.tls.clone() {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => self.upgrade(stream, address, transport, shutdown_rx).await,
+                Ok(Err(e)) => eprintln!("TLS handshake with {address} failed; err = {:?}", e),
+                Err(_) => eprintln!("TLS handshake with {address} timed out"),
+            }
+            return;
+        }
+        self.upgrade(stream, address, transport, shutdown_rx).await
+    }
 
-            let (tx, mut rx) = mpsc::unbounded_channel();
+    /// Splits the stream into frame reader and writer for the transport the listener speaks
+    async fn upgrade<IO>(self, stream: IO, address: PeerAddr, transport: Transport, shutdown_rx: watch::Receiver<bool>)
+        where
+            IO: AsyncRead + AsyncWrite + Send + Unpin + 'static
+    {
+        match transport {
+            Transport::Raw => {
+                let (read_half, write_half) = tokio::io::split(stream);
+                //buffered so several small frames coalesced in one segment don't cost a syscall each
+                self.handle_connection(BufReader::new(read_half), write_half, address, shutdown_rx).await
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket => {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, crate::websocket::accept(stream, self.max_event_size, self.authenticate.is_some())).await {
+                    Ok(Ok((reader, writer))) => self.handle_connection(reader, writer, address, shutdown_rx).await,
+                    Ok(Err(e)) => eprintln!("WebSocket handshake with {address} failed; err = {:?}", e),
+                    Err(_) => eprintln!("WebSocket handshake with {address} timed out"),
+                }
+            }
+        }
+    }
+
+    async fn handle_connection<R, W>(self, mut reader: R, mut writer: W, address: PeerAddr, mut shutdown_rx: watch::Receiver<bool>)
+        where
+            R: FrameReader,
+            W: FrameWriter + 'static
+    {
+        let identity = match &self.authenticate {
+            Some(authenticate) => {
+                let authenticated = authenticate_client(&mut reader, &mut writer, &address, authenticate, self.auth_timeout, self.max_event_size);
+                match authenticated.await {
+                    Some(identity) => Some(Arc::from(identity)),
+                    None => return,
+                }
+            }
+            None => None,
+        };
 
-            //TODO parameter
-            let mut write_buf = vec![0u8; self.max_event_size].into_boxed_slice();
+        let on_connect = self.on_connect;
+        let on_event = self.on_event;
+        let on_request = self.on_request;
+        let on_disconnect = self.on_disconnect;
 
-            let conn_id = self.connections.insert(address, tx);
-            let connections = Arc::clone(&self.connections);
+        let queue = Arc::new(EventQueue::new(self.queue_capacity, self.overflow_policy));
+        let close = Arc::new(Notify::new());
+        let rate_limit = self.rate_limit;
+        let mut bucket = rate_limit.as_ref().map(TokenBucket::new);
 
-            tokio::spawn(async move {
-                if let Ok(map) = connections.peers.read() {
-                    if let Some(conn) = map.get(&conn_id) {
-                        on_connect(conn);
-                    }
+        let conn = self.connections.insert(address, identity, Arc::clone(&queue), Arc::clone(&close));
+        let conn_id = conn.id;
+        let connections = self.connections;
+        let codec = self.codec;
+        let max_event_size = self.max_event_size;
+        let shutdown_timeout = self.shutdown_timeout;
+        let idle_timeout = self.idle_timeout;
+        let request_timeout = self.request_timeout;
+        let max_requests = self.queue_capacity;
+
+        //outbound events are written as soon as they are queued, independently of inbound traffic
+        let (closing_tx, closing_rx) = watch::channel(false);
+        let pong = Arc::new(Notify::new());
+        let mut writer = tokio::spawn(write_events(writer, queue, closing_rx, Arc::clone(&pong), self.heartbeat_interval, Arc::clone(&codec), max_event_size));
+        let mut requests = JoinSet::new();
+
+        let state = on_connect(&conn);
+        let mut context = Context { conn, state };
+        let mut buf = Vec::with_capacity(max_event_size);
+        let mut last_received = Instant::now();
+        let closing = 'connection: loop {
+            //reaped here rather than selected on, a frame read that is interrupted would lose what it read so far
+            while requests.try_join_next().is_some() {}
+            let idle = async {
+                match idle_timeout {
+                    Some(timeout) => tokio::time::sleep_until(last_received + timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = signalled(&mut shutdown_rx) => break true,
+                _ = close.notified() => break true,
+                //the peer is unreachable, not worth trying to flush to it
+                _ = idle => {
+                    eprintln!("connection {conn_id} idle, closing");
+                    break false;
                 }
-                let mut buf = vec![0; self.max_event_size].into_boxed_slice();
-                // In a loop, read data from the socket and write the data back.
-                loop {
-                    match stream.read(&mut buf).await {
-                        // socket closed
-                        Ok(n) if n == 0 => {
-                            if let Some(conn) = connections.remove(conn_id) {
-                                on_disconnect(&conn)
+                read = reader.read_frame(&mut buf, max_event_size) => match read {
+                    Ok(Some(kind)) => {
+                        last_received = Instant::now();
+                        if let (Some(bucket), Some(limit), FrameKind::Event | FrameKind::Request) = (&mut bucket, &rate_limit, kind) {
+                            if let Err(wait) = bucket.take(last_received) {
+                                match limit.action {
+                                    RateLimitAction::Drop => {
+                                        if kind == FrameKind::Request {
+                                            if let Ok((id, _)) = decode_request(codec.as_ref(), &buf) {
+                                                tokio::select! {
+                                                    _ = context.conn.respond(id, Err("rate limited".to_string())) => {}
+                                                    _ = signalled(&mut shutdown_rx) => break 'connection true,
+                                                    _ = close.notified() => break 'connection true,
+                                                }
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                    RateLimitAction::Delay => {
+                                        let delayed = async {
+                                            let mut wait = wait;
+                                            loop {
+                                                tokio::time::sleep(wait).await;
+                                                match bucket.take(Instant::now()) {
+                                                    Ok(()) => break,
+                                                    Err(left) => wait = left,
+                                                }
+                                            }
+                                        };
+                                        tokio::select! {
+                                            //waiting on the limit doesn't make the connection idle
+                                            _ = delayed => last_received = Instant::now(),
+                                            _ = signalled(&mut shutdown_rx) => break true,
+                                            _ = close.notified() => break true,
+                                        }
+                                    }
+                                    RateLimitAction::Disconnect => {
+                                        eprintln!("connection {conn_id} over its rate limit, closing");
+                                        break false;
+                                    }
+                                }
                             }
                         }
-                        Ok(n) => {
-                            //TODO use framed messages
-                            match bincode::decode_from_slice(&buf[..n], self.config) {
-                                Ok((event, _)) => {
-                                    on_event(conn_id, event);
-                                }
+                        match kind {
+                            FrameKind::Event => match codec.decode(&buf) {
+                                Ok(event) => match &on_event {
+                                    EventHandler::Sync(handler) => handler(conn_id, event),
+                                    EventHandler::Async(handler) => {
+                                        tokio::select! {
+                                            //time spent handling the event doesn't make the connection idle
+                                            _ = handler(&mut context, event) => last_received = Instant::now(),
+                                            _ = signalled(&mut shutdown_rx) => break true,
+                                            _ = close.notified() => break true,
+                                        }
+                                    }
+                                },
                                 Err(e) => {
                                     eprintln!("Failed to deserialize event: {}", e)
                                 }
+                            },
+                            FrameKind::Request => match decode_request(codec.as_ref(), &buf) {
+                                Ok((id, event)) => match &on_request {
+                                    Some(on_request) => {
+                                        //no more in flight than the queue holds, the client waits on its writes past that
+                                        while requests.len() >= max_requests {
+                                            tokio::select! {
+                                                _ = requests.join_next() => {}
+                                                _ = signalled(&mut shutdown_rx) => break 'connection true,
+                                                _ = close.notified() => break 'connection true,
+                                            }
+                                        }
+                                        let response = on_request(conn_id, event);
+                                        let conn = context.conn.clone();
+                                        requests.spawn(async move {
+                                            let response = tokio::time::timeout(request_timeout, response).await
+                                                .map_err(|_| "request timed out".to_string());
+                                            conn.respond(id, response).await;
+                                        });
+                                    }
+                                    None => tokio::select! {
+                                        _ = context.conn.respond(id, Err("no request handler".to_string())) => {}
+                                        _ = signalled(&mut shutdown_rx) => break 'connection true,
+                                        _ = close.notified() => break 'connection true,
+                                    },
+                                },
+                                Err(e) => {
+                                    eprintln!("Failed to deserialize request: {}", e)
+                                }
+                            },
+                            FrameKind::Ping => pong.notify_one(),
+                            FrameKind::Pong => {}
+                            FrameKind::Auth => eprintln!("connection {conn_id} sent credentials again"),
+                            FrameKind::Response | FrameKind::AuthAccepted | FrameKind::AuthRejected => {
+                                eprintln!("connection {conn_id} sent a {kind:?} frame, only servers do")
                             }
                         }
-                        Err(e) => {
-                            eprintln!("failed to read from socket; err = {:?}", e);
-                            if let Some(conn) = connections.remove(conn_id) {
-                                on_disconnect(&conn)
-                            }
-                            return;
-                        }
-                    };
+                    }
+                    // socket closed
+                    Ok(None) => break false,
+                    Err(e) => {
+                        eprintln!("failed to read from socket; err = {:?}", e);
+                        break false;
+                    }
+                }
+            }
+        };
 
-                    match rx.try_recv() {
-                        Ok(event) => {
-                            let size = bincode::encode_into_slice(event, &mut *write_buf, self.config).unwrap();
-                            if let Err(e) = stream.write_all(&write_buf[..size]).await {
-                                eprintln!("failed to write to socket; err = {:?}", e);
-                            }
+        //whatever requests are still running won't be answered
+        drop(requests);
+        //if the peer is gone there is no one left to flush to
+        if closing {
+            let _ = closing_tx.send(true);
+        }
+        if !closing || tokio::time::timeout(shutdown_timeout, &mut writer).await.is_err() {
+            writer.abort();
+        }
+        if let Some(conn) = connections.remove(conn_id) {
+            on_disconnect(&conn)
+        }
+
+    }
+}
+
+/// Reads the credentials and lets the client know whether they were accepted, `None` if it has to be dropped
+async fn authenticate_client<R, W>(reader: &mut R, writer: &mut W, address: &PeerAddr, authenticate: &Authenticator, timeout: Duration, max_event_size: usize) -> Option<String>
+    where
+        R: FrameReader,
+        W: FrameWriter
+{
+    let mut buf = Vec::new();
+    let authenticated = tokio::time::timeout(timeout, async {
+        match reader.read_frame(&mut buf, max_event_size).await {
+            Ok(Some(FrameKind::Auth)) => Some(authenticate(address.clone(), std::mem::take(&mut buf)).await),
+            Ok(Some(kind)) => Some(Err(format!("expected credentials, got a {kind:?} frame"))),
+            //gone already, no one to tell
+            Ok(None) | Err(_) => None,
+        }
+    }).await.unwrap_or_else(|_| Some(Err("authentication timed out".to_string())))?;
+
+    match authenticated {
+        Ok(identity) => writer.write_frame(FrameKind::AuthAccepted, &[]).await.ok().map(|_| identity),
+        Err(reason) => {
+            eprintln!("rejected {address}: {reason}");
+            if writer.write_frame(FrameKind::AuthRejected, reason.as_bytes()).await.is_ok() {
+                let _ = writer.close().await;
+            }
+            None
+        }
+    }
+}
+
+/// Writes the connection queue in order until it is closed, along with pings and pongs.
+/// When the connection is closing the queue is closed to new events and what is left is flushed before returning
+async fn write_events<T, C: Codec<T>, W: FrameWriter>(mut writer: W, queue: Arc<EventQueue<Outbound<T>>>, mut closing_rx: watch::Receiver<bool>, pong: Arc<Notify>, heartbeat_interval: Option<Duration>, codec: Arc<C>, max_event_size: usize) {
+    let mut heartbeat = heartbeat_interval.map(|period| tokio::time::interval_at(Instant::now() + period, period));
+    loop {
+        let written = tokio::select! {
+            biased;
+            outbound = queue.pop() => match outbound {
+                Some(Outbound::Event(event)) => match encode_event(codec.as_ref(), &event, max_event_size) {
+                    Ok(payload) => writer.write_frame(FrameKind::Event, &payload).await,
+                    Err(e) => {
+                        eprintln!("Failed to serialize event: {}", e);
+                        continue;
+                    }
+                },
+                Some(Outbound::Response(id, response)) => {
+                    //a response too big to send still lets the client know
+                    let payload = encode_response(codec.as_ref(), id, &response, max_event_size)
+                        .or_else(|e| encode_response(codec.as_ref(), id, &Err(e.to_string()), max_event_size));
+                    match payload {
+                        Ok(payload) => writer.write_frame(FrameKind::Response, &payload).await,
+                        Err(e) => {
+                            eprintln!("Failed to serialize response: {}", e);
+                            continue;
                         }
-                        Err(_) => {}
                     }
-
-                    // //Write the data back
-                    // if let Err(e) = stream.write_all(&buf[0..n]).await {
-                    //     eprintln!("failed to write to socket; err = {:?}", e);
-                    //     return;
-                    // }
                 }
-            });
+                None => {
+                    //both halves share the stream, it is only closed once the reader is dropped as well
+                    let _ = writer.close().await;
+                    return;
+                }
+            },
+            _ = pong.notified() => writer.write_frame(FrameKind::Pong, &[]).await,
+            _ = tick(&mut heartbeat) => writer.write_frame(FrameKind::Ping, &[]).await,
+            _ = signalled(&mut closing_rx) => {
+                //the queue keeps handing out what is left, then `None`
+                queue.close();
+                continue;
+            }
+        };
+        if let Err(e) = written {
+            eprintln!("failed to write to socket; err = {:?}", e);
+            return;
         }
     }
-}
\ No newline at end of file
+}
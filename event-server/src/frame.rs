@@ -0,0 +1,166 @@
+use std::future::Future;
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the big endian `u32` payload length and the kind byte written before every payload
+pub const HEADER_SIZE: usize = 5;
+
+/// What a frame carries, pings and pongs have an empty payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameKind {
+    Event = 0,
+    Ping = 1,
+    Pong = 2,
+    /// An event prefixed with a correlation id, answered by a `Response` with the same id
+    Request = 3,
+    Response = 4,
+    /// Credentials sent as the first frame when the server authenticates clients
+    Auth = 5,
+    AuthAccepted = 6,
+    /// Carries the reason as UTF-8, the server closes the connection after it
+    AuthRejected = 7,
+}
+
+impl FrameKind {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(FrameKind::Event),
+            1 => Some(FrameKind::Ping),
+            2 => Some(FrameKind::Pong),
+            3 => Some(FrameKind::Request),
+            4 => Some(FrameKind::Response),
+            5 => Some(FrameKind::Auth),
+            6 => Some(FrameKind::AuthAccepted),
+            7 => Some(FrameKind::AuthRejected),
+            _ => None,
+        }
+    }
+}
+
+/// Reads one frame, putting its payload into `buf`. Returns `None` if the stream was closed between frames.
+/// Frames announcing more than `max_size` bytes are rejected before reading the payload
+pub async fn read_frame<R>(reader: &mut R, buf: &mut Vec<u8>, max_size: usize) -> io::Result<Option<FrameKind>>
+    where
+        R: AsyncRead + Unpin
+{
+    let mut header = [0u8; HEADER_SIZE];
+    let mut read = 0;
+    while read < HEADER_SIZE {
+        match reader.read(&mut header[read..]).await? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len > max_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {len} bytes exceeds max size of {max_size}")));
+    }
+    let kind = FrameKind::from_byte(header[4])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown frame kind {}", header[4])))?;
+    buf.resize(len, 0);
+    reader.read_exact(buf).await?;
+    Ok(Some(kind))
+}
+
+/// Writes the header followed by the payload
+pub async fn write_frame<W>(writer: &mut W, kind: FrameKind, payload: &[u8]) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin
+{
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    let mut header = [0u8; HEADER_SIZE];
+    header[..4].copy_from_slice(&len.to_be_bytes());
+    header[4] = kind as u8;
+    writer.write_all(&header).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+
+/// Inbound side of a connection, the raw framing above or another transport carrying the same frames
+pub(crate) trait FrameReader: Send + Unpin {
+    fn read_frame(&mut self, buf: &mut Vec<u8>, max_size: usize) -> impl Future<Output = io::Result<Option<FrameKind>>> + Send;
+}
+
+/// Outbound side of a connection, see [`FrameReader`]
+pub(crate) trait FrameWriter: Send + Unpin {
+    fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Lets the peer know nothing more is coming
+    fn close(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+impl<R: AsyncRead + Send + Unpin> FrameReader for R {
+    fn read_frame(&mut self, buf: &mut Vec<u8>, max_size: usize) -> impl Future<Output = io::Result<Option<FrameKind>>> + Send {
+        read_frame(self, buf, max_size)
+    }
+}
+
+impl<W: AsyncWrite + Send + Unpin> FrameWriter for W {
+    fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> impl Future<Output = io::Result<()>> + Send {
+        write_frame(self, kind, payload)
+    }
+
+    fn close(&mut self) -> impl Future<Output = io::Result<()>> + Send {
+        self.shutdown()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_coalesced_and_split_frames() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        tokio::spawn(async move {
+            let mut coalesced = vec![];
+            for payload in [&b"first"[..], b"second"] {
+                coalesced.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                coalesced.push(FrameKind::Event as u8);
+                coalesced.extend_from_slice(payload);
+            }
+            client.write_all(&coalesced).await.unwrap();
+
+            //one frame split across writes
+            let frame = [&6u32.to_be_bytes()[..], &[FrameKind::Ping as u8], b"thi", b"rd!"];
+            for part in frame {
+                client.write_all(part).await.unwrap();
+                client.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut buf = vec![];
+        for (kind, expected) in [(FrameKind::Event, &b"first"[..]), (FrameKind::Event, b"second"), (FrameKind::Ping, b"third!")] {
+            assert_eq!(read_frame(&mut server, &mut buf, 16).await.unwrap(), Some(kind));
+            assert_eq!(buf, expected);
+        }
+        assert_eq!(read_frame(&mut server, &mut buf, 16).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_frame_too_large() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        write_frame(&mut client, FrameKind::Event, &[0u8; 17]).await.unwrap();
+
+        let err = read_frame(&mut server, &mut vec![], 16).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_frame_kind() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0, 0, 0, 0, 9]).await.unwrap();
+
+        let err = read_frame(&mut server, &mut vec![], 16).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,44 +1,85 @@
-use std::io::Write;
-use std::net::TcpStream;
-use std::thread;
-use std::thread::{JoinHandle, sleep};
-use std::time::Duration;
-use crate::event_server::EventServer;
+pub mod client;
+pub mod codec;
+pub mod event_server;
+pub mod frame;
+pub mod limit;
+pub mod listener;
+pub mod queue;
+mod rpc;
+#[cfg(all(test, feature = "bincode"))]
+mod test_util;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
 
-mod event_server;
+pub use client::{ClientState, EventClient, Reconnect};
+pub use codec::{Codec, CodecError, DefaultCodec};
+#[cfg(feature = "bincode")]
+pub use codec::BincodeCodec;
+#[cfg(feature = "json")]
+pub use codec::JsonCodec;
+#[cfg(feature = "msgpack")]
+pub use codec::MsgPackCodec;
+pub use event_server::{BoxFuture, Connection, Context, EventServer, FixedState, OpenState, ServerHandle};
+pub use limit::{RateLimit, RateLimitAction};
+pub use listener::{Listener, PeerAddr};
+pub use queue::{OverflowPolicy, QueueStats, SendError};
+pub use rpc::RequestError;
 
-const ADDRESS: &'static str = "127.0.0.1:8080";
 
-#[derive(Debug, bincode::Encode, bincode::Decode)]
-enum Events {
-    StrEvent(String),
-    IntEvent(usize),
-}
+#[cfg(all(test, feature = "bincode"))]
+mod tests {
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
 
+    use crate::client::{ClientState, EventClient};
+    use crate::event_server::EventServer;
+    use crate::frame::{read_frame, FrameKind};
+    use crate::limit::{RateLimit, RateLimitAction};
+    use crate::queue::{OverflowPolicy, SendError};
+    use crate::rpc::RequestError;
 
-fn run_client() -> JoinHandle<()> {
-    return thread::spawn(|| {
-        sleep(Duration::from_secs(3));
-        println!("[CLIENT] connecting");
-        let mut tcp = TcpStream::connect(ADDRESS).unwrap();
-        tcp.write_all(bincode::encode_to_vec(Events::IntEvent(123), bincode::config::standard()).unwrap().as_slice()).unwrap();
-        tcp.flush().unwrap();
-    });
-}
+    const ADDRESS: &str = "127.0.0.1:0";
 
+    #[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
+    enum Events {
+        StrEvent(String),
+        IntEvent(usize),
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+
+    fn run_client(address: SocketAddr) -> JoinHandle<()> {
+        thread::spawn(move || {
+            println!("[CLIENT] connecting");
+            let mut tcp = TcpStream::connect(address).unwrap();
+            let payload = bincode::encode_to_vec(Events::IntEvent(123), bincode::config::standard()).unwrap();
+            tcp.write_all(&(payload.len() as u32).to_be_bytes()).unwrap();
+            tcp.write_all(&[FrameKind::Event as u8]).unwrap();
+            tcp.write_all(&payload).unwrap();
+            tcp.flush().unwrap();
+        })
+    }
 
 
     #[tokio::test]
     async fn test() -> Result<(), Box<dyn std::error::Error>>{
+        let received = Arc::new(AtomicUsize::new(0));
+        let disconnected = Arc::new(AtomicUsize::new(0));
+        let (r, d) = (received.clone(), disconnected.clone());
 
         let server = EventServer::<Events>::builder()
             .on_connect(|conn| println!("[SERVER] Connected {:?}", conn))
-            .on_disconnect(|conn| println!("[SERVER] Disconnected {:?}", conn))
-            .on_event(|id, event| {
+            .on_disconnect(move |conn| {
+                println!("[SERVER] Disconnected {:?}", conn);
+                d.fetch_add(1, Ordering::Relaxed);
+            })
+            .on_event(move |_id, event| {
                 println!("Got event {event:?}");
                 match event {
                     Events::StrEvent(value) => {
@@ -48,15 +89,456 @@ mod tests {
                         println!("Str value {value}");
                     }
                 }
+                r.fetch_add(1, Ordering::Relaxed);
             })
-            .run(ADDRESS);
+            .run(ADDRESS)
+            .await?;
+
+        println!("Server started");
+        for client in [run_client(server.local_addr().as_tcp().unwrap()), run_client(server.local_addr().as_tcp().unwrap())] {
+            client.join().unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while received.load(Ordering::Relaxed) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await?;
 
-        run_client();
-        run_client();
-        println!("Starting server");
-        let r = server.await;
+        server.shutdown().await;
         println!("Server stopped");
-        return r;
+        assert_eq!(disconnected.load(Ordering::Relaxed), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_to_silent_client() {
+        let server = EventServer::<Events>::builder()
+            .on_connect(|conn| {
+                for i in 0..3 {
+                    conn.send(Events::IntEvent(i)).unwrap();
+                }
+            })
+            .run(ADDRESS)
+            .await
+            .unwrap();
+
+        //the client never sends anything, every queued event must still arrive in order
+        let mut client = tokio::net::TcpStream::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+        let mut buf = vec![];
+        for i in 0..3 {
+            assert_eq!(read_frame(&mut client, &mut buf, 1024).await.unwrap(), Some(FrameKind::Event));
+            let (event, _): (Events, _) = bincode::decode_from_slice(&buf, bincode::config::standard()).unwrap();
+            assert!(matches!(event, Events::IntEvent(v) if v == i));
+        }
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes_queued_events() {
+        let disconnected = Arc::new(AtomicUsize::new(0));
+        let d = disconnected.clone();
+        let server = EventServer::<Events>::builder()
+            .on_connect(|conn| {
+                for i in 0..3 {
+                    conn.send(Events::IntEvent(i)).unwrap();
+                }
+            })
+            .on_disconnect(move |_| {
+                d.fetch_add(1, Ordering::Relaxed);
+            })
+            .run(ADDRESS)
+            .await
+            .unwrap();
+
+        let mut client = tokio::net::TcpStream::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+        let mut buf = vec![];
+        //once the first event arrived the connection is accepted and the other two are queued
+        assert_eq!(read_frame(&mut client, &mut buf, 1024).await.unwrap(), Some(FrameKind::Event));
+        let shutdown = tokio::spawn(async move { server.shutdown().await });
+
+        for i in 1..3 {
+            assert_eq!(read_frame(&mut client, &mut buf, 1024).await.unwrap(), Some(FrameKind::Event));
+            let (event, _): (Events, _) = bincode::decode_from_slice(&buf, bincode::config::standard()).unwrap();
+            assert!(matches!(event, Events::IntEvent(v) if v == i));
+        }
+        assert_eq!(read_frame(&mut client, &mut buf, 1024).await.unwrap(), None);
+
+        shutdown.await.unwrap();
+        assert_eq!(disconnected.load(Ordering::Relaxed), 1);
+    }
+
+    async fn read_event(client: &mut tokio::net::TcpStream) -> Option<Events> {
+        let mut buf = vec![];
+        read_frame(client, &mut buf, 1024).await.unwrap()?;
+        Some(bincode::decode_from_slice(&buf, bincode::config::standard()).unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_handle_send_and_disconnect() {
+        let disconnected = Arc::new(AtomicUsize::new(0));
+        let d = disconnected.clone();
+        let server = EventServer::<Events>::builder()
+            .on_disconnect(move |_| {
+                d.fetch_add(1, Ordering::Relaxed);
+            })
+            .run(ADDRESS)
+            .await
+            .unwrap();
+
+        let mut clients = vec![];
+        for expected in 1..=2 {
+            clients.push(tokio::net::TcpStream::connect(server.local_addr().as_tcp().unwrap()).await.unwrap());
+            while server.connection_ids().len() < expected {
+                tokio::task::yield_now().await;
+            }
+        }
+        let ids = server.connection_ids();
+
+        server.broadcast(Events::IntEvent(1));
+        server.broadcast_except(ids[0], Events::IntEvent(2));
+        server.clone().send_to(ids[0], Events::IntEvent(3)).unwrap();
+        assert!(server.send_to(usize::MAX, Events::IntEvent(4)).is_err());
+
+        assert!(matches!(read_event(&mut clients[0]).await, Some(Events::IntEvent(1))));
+        assert!(matches!(read_event(&mut clients[0]).await, Some(Events::IntEvent(3))));
+        assert!(matches!(read_event(&mut clients[1]).await, Some(Events::IntEvent(1))));
+        assert!(matches!(read_event(&mut clients[1]).await, Some(Events::IntEvent(2))));
+
+        server.send_to(ids[1], Events::StrEvent("bye".into())).unwrap();
+        assert!(server.disconnect(ids[1]));
+        assert!(matches!(read_event(&mut clients[1]).await, Some(Events::StrEvent(v)) if v == "bye"));
+        assert!(read_event(&mut clients[1]).await.is_none());
+        while server.connection_ids().len() > 1 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(server.connection_ids(), vec![ids[0]]);
+        assert_eq!(disconnected.load(Ordering::Relaxed), 1);
+        assert!(!server.disconnect(ids[1]));
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_rooms() {
+        let server = EventServer::<Events>::builder().run(ADDRESS).await.unwrap();
+        let mut clients = vec![];
+        for expected in 1..=3 {
+            clients.push(tokio::net::TcpStream::connect(server.local_addr().as_tcp().unwrap()).await.unwrap());
+            while server.connection_ids().len() < expected {
+                tokio::task::yield_now().await;
+            }
+        }
+        let ids = server.connection_ids();
+
+        assert!(server.join(ids[0], "lobby"));
+        assert!(server.join(ids[1], "lobby"));
+        assert!(server.join(ids[1], "chat"));
+        assert!(!server.join(usize::MAX, "lobby"));
+        assert_eq!(server.members("lobby"), vec![ids[0], ids[1]]);
+        assert_eq!(server.rooms_of(ids[1]), vec!["chat", "lobby"]);
+        assert_eq!(server.rooms(), vec!["chat", "lobby"]);
+
+        server.publish("lobby", Events::IntEvent(1));
+        server.publish_except("lobby", ids[0], Events::IntEvent(2));
+        server.publish("nowhere", Events::IntEvent(3));
+        server.send_to(ids[2], Events::IntEvent(4)).unwrap();
+        assert!(matches!(read_event(&mut clients[0]).await, Some(Events::IntEvent(1))));
+        assert!(matches!(read_event(&mut clients[1]).await, Some(Events::IntEvent(1))));
+        assert!(matches!(read_event(&mut clients[1]).await, Some(Events::IntEvent(2))));
+        //nothing published reached the connection outside the rooms
+        assert!(matches!(read_event(&mut clients[2]).await, Some(Events::IntEvent(4))));
+
+        assert!(server.leave(ids[1], "chat"));
+        assert!(!server.leave(ids[1], "chat"));
+        assert_eq!(server.rooms(), vec!["lobby"]);
+
+        //disconnecting leaves every room
+        drop(clients.remove(0));
+        while server.connection_ids().len() > 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(server.members("lobby"), vec![ids[1]]);
+        assert!(server.rooms_of(ids[0]).is_empty());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_async_handler_with_state() {
+        struct Session {
+            name: String,
+            total: usize,
+        }
+
+        let (listener, connector) = crate::listener::memory(1024);
+        let server = EventServer::<Events>::builder()
+            .on_connect(|conn| Session { name: format!("player-{}", conn.id()), total: 0 })
+            .on_event_async(|ctx, event| Box::pin(async move {
+                //stands in for a database call, the next event waits for it
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                match event {
+                    Events::IntEvent(value) => {
+                        ctx.state_mut().total += value;
+                        ctx.reply(Events::IntEvent(ctx.state().total)).unwrap();
+                    }
+                    Events::StrEvent(_) => ctx.reply(Events::StrEvent(ctx.state().name.clone())).unwrap(),
+                }
+            }))
+            .serve(listener)
+            .await
+            .unwrap();
 
+        let mut clients = vec![];
+        for _ in 0..2 {
+            clients.push(EventClient::<Events>::builder().connect_memory(&connector).await.unwrap());
+        }
+        for (i, client) in clients.iter().enumerate() {
+            for value in 1..=3 {
+                client.send(Events::IntEvent(value * (i + 1))).unwrap();
+            }
+            client.send(Events::StrEvent("who am I".into())).unwrap();
+        }
+        for (i, client) in clients.iter_mut().enumerate() {
+            for total in [1, 3, 6] {
+                assert!(matches!(client.recv().await, Some(Events::IntEvent(v)) if v == total * (i + 1)));
+            }
+        }
+        //both connections are accepted by now
+        let ids = server.connection_ids();
+        for (i, client) in clients.iter_mut().enumerate() {
+            assert!(matches!(client.recv().await, Some(Events::StrEvent(name)) if name == format!("player-{}", ids[i])));
+        }
+        server.shutdown().await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_heartbeat_and_idle_timeout() {
+        let disconnected = Arc::new(AtomicUsize::new(0));
+        let d = disconnected.clone();
+        let server = EventServer::<Events>::builder()
+            .heartbeat_interval(Duration::from_millis(10))
+            .idle_timeout(Duration::from_millis(50))
+            .on_disconnect(move |_| {
+                d.fetch_add(1, Ordering::Relaxed);
+            })
+            .run(ADDRESS)
+            .await
+            .unwrap();
+
+        //answers pings on its own, stays connected without sending anything
+        let client = EventClient::<Events>::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+        //gets pings but never answers
+        let mut silent = tokio::net::TcpStream::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+
+        let mut buf = vec![];
+        assert_eq!(read_frame(&mut silent, &mut buf, 1024).await.unwrap(), Some(FrameKind::Ping));
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while read_frame(&mut silent, &mut buf, 1024).await.unwrap().is_some() {}
+            while disconnected.load(Ordering::Relaxed) == 0 {
+                tokio::task::yield_now().await;
+            }
+        }).await.unwrap();
+
+        assert_eq!(disconnected.load(Ordering::Relaxed), 1);
+        assert_eq!(server.connection_ids().len(), 1);
+        assert_eq!(client.state(), ClientState::Connected);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_slow_consumer() {
+        let disconnected = Arc::new(AtomicUsize::new(0));
+        let d = disconnected.clone();
+        let server = EventServer::<Events>::builder()
+            .queue_capacity(2)
+            .overflow_policy(OverflowPolicy::Disconnect)
+            .on_connect(|conn| {
+                //the writer can't run before the callback returns, so the queue fills up
+                conn.send(Events::IntEvent(0)).unwrap();
+                conn.send(Events::IntEvent(1)).unwrap();
+                assert_eq!(conn.queue_stats().len, 2);
+                assert!(matches!(conn.send(Events::IntEvent(2)), Err(SendError::Full(Events::IntEvent(2)))));
+                assert!(matches!(conn.send(Events::IntEvent(3)), Err(SendError::Closed(_))));
+            })
+            .on_disconnect(move |conn| {
+                assert_eq!(conn.queue_stats().dropped, 3);
+                d.fetch_add(1, Ordering::Relaxed);
+            })
+            .run(ADDRESS)
+            .await
+            .unwrap();
+
+        let mut client = tokio::net::TcpStream::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+        assert!(read_event(&mut client).await.is_none());
+        //the asserts in the callbacks panic the connection task, which would leave this waiting
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while disconnected.load(Ordering::Relaxed) == 0 {
+                tokio::task::yield_now().await;
+            }
+        }).await.unwrap();
+        assert!(server.connection_ids().is_empty());
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_authentication() {
+        let (connected_tx, mut connected_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (listener, connector) = crate::listener::memory(1024);
+        let server = EventServer::<Events>::builder()
+            .authenticate(|_, credentials| async move {
+                match credentials.as_slice() {
+                    b"secret" => Ok("alice".to_string()),
+                    _ => Err("wrong password".to_string()),
+                }
+            })
+            .auth_timeout(Duration::from_millis(100))
+            .on_connect(move |conn| connected_tx.send(conn.identity().map(str::to_string)).unwrap())
+            .serve(listener)
+            .await
+            .unwrap();
+
+        let client = EventClient::<Events>::builder().auth("secret").connect_memory(&connector).await.unwrap();
+        assert_eq!(connected_rx.recv().await, Some(Some("alice".to_string())));
+        assert_eq!(client.state(), ClientState::Connected);
+
+        let Err(e) = EventClient::<Events>::builder().auth("guess").connect_memory(&connector).await else { panic!("accepted wrong credentials") };
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(e.to_string(), "wrong password");
+
+        //a client that never authenticates is dropped without ever counting as connected
+        let mut silent = connector.connect().unwrap();
+        let mut buf = vec![];
+        assert_eq!(read_frame(&mut silent, &mut buf, 64).await.unwrap(), Some(FrameKind::AuthRejected));
+        assert_eq!(buf, b"authentication timed out");
+        assert_eq!(read_frame(&mut silent, &mut buf, 64).await.unwrap(), None);
+        assert_eq!(server.connection_ids().len(), 1);
+        assert!(connected_rx.try_recv().is_err());
+
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_connection_and_rate_limits() {
+        let server = EventServer::<Events>::builder()
+            .max_connections_per_ip(1)
+            .rate_limit(RateLimit { per_second: 0.001, burst: 2, action: RateLimitAction::Drop })
+            .on_request(|_, event| async move { event })
+            .run(ADDRESS)
+            .await
+            .unwrap();
+        let address = server.local_addr().as_tcp().unwrap();
+
+        let client = EventClient::<Events>::connect(address).await.unwrap();
+        for _ in 0..2 {
+            assert!(client.request(Events::IntEvent(1), Duration::from_secs(5)).await.is_ok());
+        }
+        let rejected = client.request(Events::IntEvent(1), Duration::from_secs(5)).await;
+        assert_eq!(rejected.unwrap_err(), RequestError::Failed("rate limited".to_string()));
+
+        //same IP as the client above
+        let mut refused = tokio::net::TcpStream::connect(address).await.unwrap();
+        assert!(read_event(&mut refused).await.is_none());
+        assert_eq!(server.connection_ids().len(), 1);
+        server.shutdown().await;
+
+        let (received_tx, mut received_rx) = tokio::sync::mpsc::unbounded_channel();
+        let server = EventServer::<Events>::builder()
+            .rate_limit(RateLimit { per_second: 0.001, burst: 1, action: RateLimitAction::Disconnect })
+            .on_event(move |_, event| received_tx.send(event).unwrap())
+            .run(ADDRESS)
+            .await
+            .unwrap();
+
+        let client = EventClient::<Events>::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+        client.send(Events::IntEvent(1)).unwrap();
+        client.send(Events::IntEvent(2)).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), client.disconnected()).await.unwrap();
+        assert!(matches!(received_rx.recv().await, Some(Events::IntEvent(1))));
+        assert!(received_rx.try_recv().is_err());
+        server.shutdown().await;
+    }
+
+    #[test]
+    #[should_panic(expected = "Queue capacity must be at least 1")]
+    fn test_zero_queue_capacity() {
+        let _ = EventServer::<Events>::builder().queue_capacity(0);
+    }
+
+    #[tokio::test]
+    async fn test_request_finishing_mid_frame() {
+        use tokio::io::AsyncWriteExt;
+
+        let (received_tx, mut received_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (listener, connector) = crate::listener::memory(1024);
+        let server = EventServer::<Events>::builder()
+            .on_request(|_, event| async move { event })
+            .on_event(move |_, event| received_tx.send(event).unwrap())
+            .serve(listener)
+            .await
+            .unwrap();
+
+        let mut client = connector.connect().unwrap();
+        let codec = crate::codec::BincodeCodec::default();
+        let request = crate::rpc::encode_request(&codec, 1, &Events::IntEvent(1), 1024).unwrap();
+        crate::frame::write_frame(&mut client, FrameKind::Request, &request).await.unwrap();
+
+        //the request completes while the server is halfway through the next frame
+        let payload = bincode::encode_to_vec(Events::IntEvent(2), bincode::config::standard()).unwrap();
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.push(FrameKind::Event as u8);
+        frame.extend_from_slice(&payload);
+        client.write_all(&frame[..3]).await.unwrap();
+        let mut buf = vec![];
+        assert_eq!(read_frame(&mut client, &mut buf, 1024).await.unwrap(), Some(FrameKind::Response));
+        client.write_all(&frame[3..]).await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(5), received_rx.recv()).await.unwrap();
+        assert!(matches!(received, Some(Events::IntEvent(2))));
+        assert_eq!(server.connection_ids().len(), 1);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_responses_share_the_bounded_queue() {
+        let (listener, connector) = crate::listener::memory(64);
+        let server = EventServer::<Events>::builder()
+            .queue_capacity(2)
+            .overflow_policy(OverflowPolicy::DropNewest)
+            //nothing can be flushed to a client that doesn't read
+            .shutdown_timeout(Duration::from_millis(100))
+            .on_request(|_, event| async move { event })
+            .serve(listener)
+            .await
+            .unwrap();
+
+        //never reads, so the responses pile up on the server
+        let mut client = connector.connect().unwrap();
+        let codec = crate::codec::BincodeCodec::default();
+        tokio::spawn(async move {
+            for id in 0..20 {
+                let request = crate::rpc::encode_request(&codec, id, &Events::IntEvent(id as usize), 1024).unwrap();
+                crate::frame::write_frame(&mut client, FrameKind::Request, &request).await.unwrap();
+            }
+            std::future::pending::<()>().await;
+        });
+
+        let stats = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(stats) = server.connection_ids().first().and_then(|id| server.queue_stats(*id)) {
+                    if stats.dropped > 0 {
+                        return stats;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert!(stats.max_len <= 2);
+        server.shutdown().await;
+    }
+
+    #[test]
+    #[should_panic(expected = "Rate limit must allow at least some events")]
+    fn test_empty_rate_limit() {
+        let _ = EventServer::<Events>::builder().rate_limit(RateLimit { per_second: f64::NAN, burst: 5, action: RateLimitAction::Drop });
+    }
+}
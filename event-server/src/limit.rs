@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::listener::PeerAddr;
+
+/// Token bucket on the events and requests each connection sends, pings don't count
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Rate the bucket refills at
+    pub per_second: f64,
+    /// Events allowed back to back, the bucket starts full
+    pub burst: u32,
+    pub action: RateLimitAction,
+}
+
+/// What happens to an event received while the connection is over its rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Drop the event, requests are answered with an error
+    Drop,
+    /// Stop reading from the connection until the event fits, the client ends up waiting on its own writes
+    Delay,
+    /// Close the connection without flushing
+    Disconnect,
+}
+
+pub(crate) struct TokenBucket {
+    per_second: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: &RateLimit) -> Self {
+        Self {
+            per_second: limit.per_second,
+            burst: limit.burst as f64,
+            tokens: limit.burst as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or tells how long until there is one
+    pub(crate) fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.per_second))
+        }
+    }
+}
+
+/// Counts the open connections, in total and by IP, shared by every listener of a server
+pub(crate) struct ConnectionLimits {
+    max_connections: Option<usize>,
+    max_per_ip: Option<usize>,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Holds a connection's place until dropped
+pub(crate) struct ConnectionPermit {
+    limits: Arc<ConnectionLimits>,
+    ip: Option<IpAddr>,
+}
+
+impl ConnectionLimits {
+    pub(crate) fn new(max_connections: Option<usize>, max_per_ip: Option<usize>) -> Arc<Self> {
+        Arc::new(Self {
+            max_connections,
+            max_per_ip,
+            counts: Default::default(),
+        })
+    }
+
+    /// `None` if accepting the connection would go over a limit.
+    /// Only TCP and WebSocket clients have an IP, the others only count towards the total
+    pub(crate) fn acquire(self: &Arc<Self>, address: &PeerAddr) -> Option<ConnectionPermit> {
+        let ip = address.as_tcp().map(|addr| addr.ip());
+        let mut counts = self.counts.lock().unwrap();
+        if self.max_connections.is_some_and(|max| counts.total >= max) {
+            return None;
+        }
+        if let (Some(ip), Some(max)) = (ip, self.max_per_ip) {
+            if counts.by_ip.get(&ip).is_some_and(|&count| count >= max) {
+                return None;
+            }
+        }
+        counts.total += 1;
+        if let Some(ip) = ip {
+            *counts.by_ip.entry(ip).or_default() += 1;
+        }
+        Some(ConnectionPermit { limits: Arc::clone(self), ip })
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut counts = self.limits.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(ip) = self.ip {
+            let count = counts.by_ip.get_mut(&ip).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                counts.by_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limit = RateLimit { per_second: 10.0, burst: 2, action: RateLimitAction::Drop };
+        let mut bucket = TokenBucket::new(&limit);
+        let start = bucket.refilled;
+        assert_eq!(bucket.take(start), Ok(()));
+        assert_eq!(bucket.take(start), Ok(()));
+        assert_eq!(bucket.take(start), Err(Duration::from_millis(100)));
+        assert_eq!(bucket.take(start + Duration::from_millis(100)), Ok(()));
+        //refills up to the burst only
+        assert_eq!(bucket.take(start + Duration::from_secs(10)), Ok(()));
+        assert_eq!(bucket.take(start + Duration::from_secs(10)), Ok(()));
+        assert!(bucket.take(start + Duration::from_secs(10)).is_err());
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let limits = ConnectionLimits::new(Some(3), Some(2));
+        let a = PeerAddr::from(SocketAddr::from(([10, 0, 0, 1], 1000)));
+        let b = PeerAddr::from(SocketAddr::from(([10, 0, 0, 2], 1000)));
+
+        let first = limits.acquire(&a).unwrap();
+        let _second = limits.acquire(&a).unwrap();
+        assert!(limits.acquire(&a).is_none());
+        let _third = limits.acquire(&b).unwrap();
+        assert!(limits.acquire(&PeerAddr::Memory(1)).is_none());
+
+        drop(first);
+        assert!(limits.acquire(&a).is_some());
+        assert_eq!(limits.counts.lock().unwrap().by_ip.len(), 2);
+    }
+}
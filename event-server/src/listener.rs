@@ -0,0 +1,178 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Where connections come from, see [`crate::EventServer::serve`]
+pub trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::Stream, PeerAddr)>> + Send;
+
+    fn local_addr(&self) -> io::Result<PeerAddr>;
+}
+
+/// Address of either end of a connection, whatever the transport
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// `None` for unnamed sockets, which is what connecting clients usually are
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+    /// Numbered in connection order, the listener itself is 0
+    Memory(usize),
+}
+
+impl PeerAddr {
+    pub fn as_tcp(&self) -> Option<SocketAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            PeerAddr::Unix(Some(path)) => write!(f, "{}", path.display()),
+            #[cfg(unix)]
+            PeerAddr::Unix(None) => write!(f, "(unnamed)"),
+            PeerAddr::Memory(id) => write!(f, "memory:{id}"),
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        PeerAddr::Tcp(addr)
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(Self::Stream, PeerAddr)> {
+        let (stream, address) = TcpListener::accept(self).await?;
+        Ok((stream, PeerAddr::Tcp(address)))
+    }
+
+    fn local_addr(&self) -> io::Result<PeerAddr> {
+        TcpListener::local_addr(self).map(PeerAddr::Tcp)
+    }
+}
+
+#[cfg(unix)]
+fn unix_addr(addr: tokio::net::unix::SocketAddr) -> PeerAddr {
+    PeerAddr::Unix(addr.as_pathname().map(PathBuf::from))
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&mut self) -> io::Result<(Self::Stream, PeerAddr)> {
+        let (stream, address) = tokio::net::UnixListener::accept(self).await?;
+        Ok((stream, unix_addr(address)))
+    }
+
+    fn local_addr(&self) -> io::Result<PeerAddr> {
+        tokio::net::UnixListener::local_addr(self).map(unix_addr)
+    }
+}
+
+/// In process listener, clients connect through the [`MemoryConnector`] created along with it
+pub struct MemoryListener {
+    rx: mpsc::UnboundedReceiver<DuplexStream>,
+    accepted: usize,
+}
+
+/// Opens connections to a [`MemoryListener`], cheap to clone
+#[derive(Clone)]
+pub struct MemoryConnector {
+    tx: mpsc::UnboundedSender<DuplexStream>,
+    buffer_size: usize,
+}
+
+/// Listener and connector pair, `buffer_size` is how many bytes each direction of a connection holds before writes wait
+pub fn memory(buffer_size: usize) -> (MemoryListener, MemoryConnector) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (MemoryListener { rx, accepted: 0 }, MemoryConnector { tx, buffer_size })
+}
+
+impl MemoryConnector {
+    /// Fails once the listener is dropped, which is what a stopped server does
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (client, server) = tokio::io::duplex(self.buffer_size);
+        self.tx.send(server).map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+}
+
+impl Listener for MemoryListener {
+    type Stream = DuplexStream;
+
+    async fn accept(&mut self) -> io::Result<(Self::Stream, PeerAddr)> {
+        match self.rx.recv().await {
+            Some(stream) => {
+                self.accepted += 1;
+                Ok((stream, PeerAddr::Memory(self.accepted)))
+            }
+            //every connector is gone so no one can connect anymore, the server still runs until shut down
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<PeerAddr> {
+        Ok(PeerAddr::Memory(0))
+    }
+}
+
+
+#[cfg(all(test, feature = "bincode"))]
+mod tests {
+    use super::*;
+    use crate::client::EventClient;
+    use crate::test_util::{assert_round_trip, welcome_server, Events};
+
+    #[tokio::test]
+    async fn test_memory_transport() {
+        let (listener, connector) = memory(1024);
+        let (server, mut probe) = welcome_server();
+        let server = server.serve(listener).await.unwrap();
+        assert_eq!(server.local_addr(), &PeerAddr::Memory(0));
+
+        let mut client = EventClient::<Events>::builder().connect_memory(&connector).await.unwrap();
+        assert_round_trip(&mut client, &mut probe, 1).await;
+        assert_eq!(probe.connected.recv().await, Some(PeerAddr::Memory(1)));
+
+        server.shutdown().await;
+        assert_eq!(client.recv().await, None);
+        assert!(connector.connect().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_transport() {
+        let path = std::env::temp_dir().join(format!("event-server-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (server, mut probe) = welcome_server();
+        let server = server.serve(tokio::net::UnixListener::bind(&path).unwrap()).await.unwrap();
+        assert_eq!(server.local_addr(), &PeerAddr::Unix(Some(path.clone())));
+
+        let mut client = EventClient::<Events>::builder().connect_unix(&path).await.unwrap();
+        assert_round_trip(&mut client, &mut probe, 2).await;
+        assert_eq!(probe.connected.recv().await, Some(PeerAddr::Unix(None)));
+
+        server.shutdown().await;
+        std::fs::remove_file(&path).unwrap();
+    }
+}
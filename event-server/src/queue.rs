@@ -0,0 +1,285 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+/// What happens to an event queued while the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Make room by dropping the event that has been waiting the longest
+    DropOldest,
+    /// Drop the event being queued
+    DropNewest,
+    /// Reject the event, `send_async` waits for room instead
+    Block,
+    /// Drop everything queued and close the connection, the consumer can't keep up
+    Disconnect,
+}
+
+/// Why an event couldn't be queued, gives the event back
+#[derive(PartialEq, Eq)]
+pub enum SendError<T> {
+    /// The connection is closed or closing
+    Closed(T),
+    /// The queue is full, see [`OverflowPolicy`]
+    Full(T),
+}
+
+impl<T> SendError<T> {
+    pub fn into_inner(self) -> T {
+        match self {
+            SendError::Closed(event) | SendError::Full(event) => event,
+        }
+    }
+
+    pub(crate) fn map<U>(self, f: impl FnOnce(T) -> U) -> SendError<U> {
+        match self {
+            SendError::Closed(event) => SendError::Closed(f(event)),
+            SendError::Full(event) => SendError::Full(f(event)),
+        }
+    }
+}
+
+impl<T> Debug for SendError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Closed(_) => f.write_str("Closed(..)"),
+            SendError::Full(_) => f.write_str("Full(..)"),
+        }
+    }
+}
+
+impl<T> Display for SendError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Closed(_) => write!(f, "connection closed"),
+            SendError::Full(_) => write!(f, "queue full"),
+        }
+    }
+}
+
+impl<T> Error for SendError<T> {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Events waiting to be written
+    pub len: usize,
+    /// Highest `len` seen so far
+    pub max_len: usize,
+    pub capacity: usize,
+    /// Events dropped by the overflow policy so far
+    pub dropped: u64,
+}
+
+/// Bounded queue of outbound events with a single consumer
+pub(crate) struct EventQueue<T> {
+    state: Mutex<State<T>>,
+    items: Notify,
+    space: Notify,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+    max_len: usize,
+    dropped: u64,
+}
+
+impl<T> EventQueue<T> {
+    pub(crate) fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        assert!(capacity > 0, "Queue capacity must be at least 1");
+        Self {
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                closed: false,
+                max_len: 0,
+                dropped: 0,
+            }),
+            items: Notify::new(),
+            space: Notify::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    /// Queues the event applying the overflow policy, never waits
+    pub(crate) fn push(&self, item: T) -> Result<(), SendError<T>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(SendError::Closed(item));
+        }
+        if state.items.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    state.dropped += 1;
+                }
+                OverflowPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return Err(SendError::Full(item));
+                }
+                OverflowPolicy::Block => return Err(SendError::Full(item)),
+                OverflowPolicy::Disconnect => {
+                    state.dropped += state.items.len() as u64 + 1;
+                    state.items.clear();
+                    state.closed = true;
+                    drop(state);
+                    self.wake_all();
+                    return Err(SendError::Full(item));
+                }
+            }
+        }
+        state.items.push_back(item);
+        state.max_len = state.max_len.max(state.items.len());
+        drop(state);
+        self.items.notify_one();
+        Ok(())
+    }
+
+    /// Same as `push`, but with the `Block` policy waits for room instead of failing
+    pub(crate) async fn push_wait(&self, mut item: T) -> Result<(), SendError<T>> {
+        loop {
+            let space = self.space.notified();
+            match self.push(item) {
+                Err(SendError::Full(rejected)) if self.policy == OverflowPolicy::Block => item = rejected,
+                result => return result,
+            }
+            space.await;
+        }
+    }
+
+    /// Puts back an event that was popped but couldn't be delivered, so it is the next one out.
+    /// Only the drop policies drop an event to make room for it
+    pub(crate) fn requeue(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+        state.items.push_front(item);
+        if state.items.len() > self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => { state.items.pop_front(); }
+                OverflowPolicy::DropNewest => { state.items.pop_back(); }
+                OverflowPolicy::Block | OverflowPolicy::Disconnect => return,
+            }
+            state.dropped += 1;
+        }
+        drop(state);
+        self.items.notify_one();
+    }
+
+    /// Waits for the next event, `None` once the queue is closed and empty
+    pub(crate) async fn pop(&self) -> Option<T> {
+        loop {
+            let notified = self.items.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(item) = state.items.pop_front() {
+                    drop(state);
+                    self.space.notify_one();
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Rejects new events, the ones already queued can still be popped
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.wake_all();
+    }
+
+    fn wake_all(&self) {
+        self.items.notify_one();
+        self.space.notify_waiters();
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    pub(crate) fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            len: state.items.len(),
+            max_len: state.max_len,
+            capacity: self.capacity,
+            dropped: state.dropped,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_overflow_policies() {
+        let queue = EventQueue::new(2, OverflowPolicy::DropOldest);
+        for i in 0..4 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.stats().dropped, 2);
+        assert_eq!(queue.pop().await, Some(2));
+        queue.requeue(2);
+        assert_eq!(queue.pop().await, Some(2));
+        assert_eq!(queue.pop().await, Some(3));
+
+        let queue = EventQueue::new(2, OverflowPolicy::DropNewest);
+        for i in 0..2 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.push(2), Err(SendError::Full(2)));
+        assert_eq!(queue.stats(), QueueStats { len: 2, max_len: 2, capacity: 2, dropped: 1 });
+        queue.close();
+        assert_eq!(queue.push(4), Err(SendError::Closed(4)));
+        assert_eq!(queue.pop().await, Some(0));
+        assert_eq!(queue.pop().await, Some(1));
+        assert_eq!(queue.pop().await, None);
+
+        let queue = EventQueue::new(2, OverflowPolicy::Disconnect);
+        for i in 0..2 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.push(2), Err(SendError::Full(2)));
+        assert!(queue.is_closed());
+        assert_eq!(queue.stats().dropped, 3);
+        assert_eq!(queue.pop().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_room() {
+        let queue = Arc::new(EventQueue::new(1, OverflowPolicy::Block));
+        queue.push(0).unwrap();
+        assert_eq!(queue.push(1), Err(SendError::Full(1)));
+
+        let producer = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move { queue.push_wait(1).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!producer.is_finished());
+
+        assert_eq!(queue.pop().await, Some(0));
+        producer.await.unwrap().unwrap();
+        assert_eq!(queue.pop().await, Some(1));
+
+        //closing releases blocked producers
+        queue.push(2).unwrap();
+        let producer = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move { queue.push_wait(3).await }
+        });
+        tokio::task::yield_now().await;
+        queue.close();
+        assert_eq!(producer.await.unwrap(), Err(SendError::Closed(3)));
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::oneshot;
+
+use crate::codec::{check_size, Codec, CodecError};
+
+/// Correlation id written before the request and response payloads
+const ID_SIZE: usize = 8;
+const STATUS_OK: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+/// Answer to a request, or why the server couldn't give one
+pub(crate) type Response<T> = Result<T, String>;
+
+/// Why a request didn't get a response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    /// The client is disconnected, or disconnected while waiting
+    Closed,
+    /// The queue is full, see [`crate::OverflowPolicy`]
+    Full,
+    /// No response in time, a late one is ignored
+    TimedOut,
+    /// The server couldn't answer, no handler or it timed out on its side
+    Failed(String),
+}
+
+impl Display for RequestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Closed => write!(f, "connection closed"),
+            RequestError::Full => write!(f, "queue full"),
+            RequestError::TimedOut => write!(f, "request timed out"),
+            RequestError::Failed(message) => write!(f, "request failed: {message}"),
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+/// The correlation id followed by the encoded event
+pub(crate) fn encode_request<T, C: Codec<T>>(codec: &C, id: u64, event: &T, max_event_size: usize) -> Result<Vec<u8>, CodecError> {
+    let mut payload = id.to_be_bytes().to_vec();
+    codec.encode(event, &mut payload)?;
+    check_size(payload, max_event_size)
+}
+
+pub(crate) fn decode_request<T, C: Codec<T>>(codec: &C, frame: &[u8]) -> Result<(u64, T), CodecError> {
+    let (id, body) = split_id(frame)?;
+    Ok((id, codec.decode(body)?))
+}
+
+/// The correlation id, a status byte and then the encoded event or the UTF-8 error message
+pub(crate) fn encode_response<T, C: Codec<T>>(codec: &C, id: u64, response: &Response<T>, max_event_size: usize) -> Result<Vec<u8>, CodecError> {
+    let mut payload = id.to_be_bytes().to_vec();
+    match response {
+        Ok(event) => {
+            payload.push(STATUS_OK);
+            codec.encode(event, &mut payload)?;
+        }
+        Err(message) => {
+            payload.push(STATUS_FAILED);
+            payload.extend_from_slice(message.as_bytes());
+        }
+    }
+    check_size(payload, max_event_size)
+}
+
+pub(crate) fn decode_response<T, C: Codec<T>>(codec: &C, frame: &[u8]) -> Result<(u64, Response<T>), CodecError> {
+    let (id, body) = split_id(frame)?;
+    match body.split_first() {
+        Some((&STATUS_OK, event)) => Ok((id, Ok(codec.decode(event)?))),
+        Some((&STATUS_FAILED, message)) => Ok((id, Err(String::from_utf8_lossy(message).into_owned()))),
+        _ => Err(CodecError::new("invalid response status")),
+    }
+}
+
+fn split_id(frame: &[u8]) -> Result<(u64, &[u8]), CodecError> {
+    if frame.len() < ID_SIZE {
+        return Err(CodecError::new("frame too short for a correlation id"));
+    }
+    let (id, body) = frame.split_at(ID_SIZE);
+    Ok((u64::from_be_bytes(id.try_into().unwrap()), body))
+}
+
+/// Requests waiting for their response, by correlation id
+pub(crate) struct Pending<T> {
+    next_id: AtomicU64,
+    waiting: Mutex<HashMap<u64, oneshot::Sender<Response<T>>>>,
+}
+
+impl<T> Pending<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            waiting: Default::default(),
+        }
+    }
+
+    pub(crate) fn register(&self) -> (u64, oneshot::Receiver<Response<T>>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(id, tx);
+        (id, rx)
+    }
+
+    /// Hands the response to whoever waits for it, nothing happens if it gave up already
+    pub(crate) fn resolve(&self, id: u64, response: Response<T>) {
+        if let Some(tx) = self.waiting.lock().unwrap().remove(&id) {
+            let _ = tx.send(response);
+        }
+    }
+
+    pub(crate) fn cancel(&self, id: u64) {
+        self.waiting.lock().unwrap().remove(&id);
+    }
+
+    /// Fails every request still waiting with [`RequestError::Closed`]
+    pub(crate) fn clear(&self) {
+        self.waiting.lock().unwrap().clear();
+    }
+}
+
+
+#[cfg(all(test, feature = "bincode"))]
+mod tests {
+    use super::*;
+    use crate::codec::BincodeCodec;
+
+    #[test]
+    fn test_request_and_response_payloads() {
+        let codec = BincodeCodec::default();
+        let payload = encode_request(&codec, 7, &42u32, 64).unwrap();
+        assert_eq!(decode_request::<u32, _>(&codec, &payload).unwrap(), (7, 42));
+
+        for response in [Ok(5u32), Err("no handler".to_string())] {
+            let payload = encode_response(&codec, u64::MAX, &response, 64).unwrap();
+            assert_eq!(decode_response(&codec, &payload).unwrap(), (u64::MAX, response));
+        }
+        assert!(decode_response::<u32, _>(&codec, &[0; 7]).is_err());
+        assert!(encode_request(&codec, 0, &vec![0u8; 64], 64).is_err());
+    }
+}
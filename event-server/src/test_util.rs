@@ -0,0 +1,39 @@
+use tokio::sync::mpsc;
+
+use crate::client::EventClient;
+use crate::event_server::EventServer;
+use crate::listener::PeerAddr;
+
+#[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode)]
+pub(crate) enum Events {
+    Welcome,
+    Value(u32),
+}
+
+/// What the server built by [`welcome_server`] saw
+pub(crate) struct Probe {
+    pub(crate) connected: mpsc::UnboundedReceiver<PeerAddr>,
+    pub(crate) received: mpsc::UnboundedReceiver<(usize, Events)>,
+}
+
+/// Greets every client with `Welcome`, reporting the address of each client and the events they send
+pub(crate) fn welcome_server() -> (EventServer<Events>, Probe) {
+    let (connected_tx, connected) = mpsc::unbounded_channel();
+    let (received_tx, received) = mpsc::unbounded_channel();
+    let server = EventServer::<Events>::builder()
+        .on_connect(move |conn| {
+            connected_tx.send(conn.address().clone()).unwrap();
+            conn.send(Events::Welcome).unwrap();
+        })
+        .on_event(move |conn_id, event| received_tx.send((conn_id, event)).unwrap());
+    (server, Probe { connected, received })
+}
+
+/// Waits for the greeting and sends `value`, returning the id of the connection the server got it from
+pub(crate) async fn assert_round_trip(client: &mut EventClient<Events>, probe: &mut Probe, value: u32) -> usize {
+    assert_eq!(client.recv().await, Some(Events::Welcome));
+    client.send(Events::Value(value)).unwrap();
+    let (conn_id, event) = probe.received.recv().await.unwrap();
+    assert_eq!(event, Events::Value(value));
+    conn_id
+}
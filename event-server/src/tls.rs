@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+fn invalid_data(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no private key in {}", path.display())))
+}
+
+/// Server config from a PEM certificate chain and its PEM private key, for [`crate::EventServer::tls`]
+pub fn server_config(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> io::Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path.as_ref())?, load_key(key_path.as_ref())?)
+        .map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+/// Client config trusting only the PEM certificates in `ca_path`, for [`crate::client::ClientBuilder::tls`]
+pub fn client_config(ca_path: impl AsRef<Path>) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path.as_ref())? {
+        roots.add(cert).map_err(invalid_data)?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+
+#[cfg(all(test, feature = "bincode"))]
+mod tests {
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::client::EventClient;
+    use crate::test_util::{assert_round_trip, welcome_server, Events};
+
+    /// Self signed certificate for localhost, written to a temporary directory removed once it is dropped
+    fn self_signed() -> (TempDir, PathBuf, PathBuf) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = TempDir::new().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        (dir, cert_path, key_path)
+    }
+
+    #[tokio::test]
+    async fn test_tls_round_trip() {
+        let (_dir, cert_path, key_path) = self_signed();
+        let (server, mut probe) = welcome_server();
+        let server = server.tls(server_config(&cert_path, &key_path).unwrap()).run("127.0.0.1:0").await.unwrap();
+
+        let mut client = EventClient::<Events>::builder()
+            .tls(client_config(&cert_path).unwrap(), "localhost")
+            .connect(server.local_addr().as_tcp().unwrap())
+            .await
+            .unwrap();
+        assert_round_trip(&mut client, &mut probe, 42).await;
+
+        //plain TCP clients never get past the handshake
+        let mut plain = EventClient::<Events>::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+        plain.send(Events::Value(1)).unwrap();
+        assert_eq!(plain.recv().await, None);
+
+        server.shutdown().await;
+    }
+}
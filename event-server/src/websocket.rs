@@ -0,0 +1,143 @@
+use std::io;
+
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::frame::{FrameKind, FrameReader, FrameWriter};
+
+/// Every binary message carries one encoded event, text messages are taken as events as well for text codecs
+pub(crate) struct WsReader<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    /// The first message carries the credentials when the server authenticates clients
+    auth_pending: bool,
+}
+
+pub(crate) struct WsWriter<S>(SplitSink<WebSocketStream<S>, Message>);
+
+/// Answers the HTTP upgrade, messages bigger than `max_event_size` close the connection
+pub(crate) async fn accept<S>(stream: S, max_event_size: usize, authenticate: bool) -> Result<(WsReader<S>, WsWriter<S>), Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin
+{
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(max_event_size))
+        .max_frame_size(Some(max_event_size));
+    let (sink, stream) = tokio_tungstenite::accept_async_with_config(stream, Some(config)).await?.split();
+    Ok((WsReader { stream, auth_pending: authenticate }, WsWriter(sink)))
+}
+
+fn into_io_error(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        Error::Capacity(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+        e => io::Error::other(e),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> FrameReader for WsReader<S> {
+    async fn read_frame(&mut self, buf: &mut Vec<u8>, _max_size: usize) -> io::Result<Option<FrameKind>> {
+        loop {
+            let payload = match self.stream.next().await {
+                Some(Ok(Message::Binary(data))) => data,
+                Some(Ok(Message::Text(text))) => text.into(),
+                Some(Ok(Message::Ping(_))) => return Ok(Some(FrameKind::Ping)),
+                Some(Ok(Message::Pong(_))) => return Ok(Some(FrameKind::Pong)),
+                Some(Ok(Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | Some(Err(Error::ConnectionClosed)) | None => return Ok(None),
+                Some(Err(e)) => return Err(into_io_error(e)),
+            };
+            buf.clear();
+            buf.extend_from_slice(&payload);
+            if std::mem::take(&mut self.auth_pending) {
+                return Ok(Some(FrameKind::Auth));
+            }
+            return Ok(Some(FrameKind::Event));
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> FrameWriter for WsWriter<S> {
+    async fn write_frame(&mut self, kind: FrameKind, payload: &[u8]) -> io::Result<()> {
+        let message = match kind {
+            FrameKind::Event => Message::binary(payload.to_vec()),
+            FrameKind::Ping => Message::Ping(Default::default()),
+            //WebSocket answers pings on its own
+            FrameKind::Pong => return Ok(()),
+            //the server closes the connection right after, the reason goes with the close frame
+            FrameKind::AuthRejected => return self.0.send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: String::from_utf8_lossy(payload).as_ref().into(),
+            }))).await.map_err(into_io_error),
+            FrameKind::AuthAccepted => return Ok(()),
+            //WebSocket clients can't send requests, so there is never a response to write
+            FrameKind::Request | FrameKind::Response | FrameKind::Auth => return Err(io::ErrorKind::Unsupported.into()),
+        };
+        self.0.send(message).await.map_err(into_io_error)
+    }
+
+    async fn close(&mut self) -> io::Result<()> {
+        self.0.close().await.map_err(into_io_error)
+    }
+}
+
+
+#[cfg(all(test, feature = "bincode"))]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::client::EventClient;
+    use crate::test_util::{assert_round_trip, welcome_server, Events};
+
+    fn encode(event: &Events) -> Vec<u8> {
+        bincode::encode_to_vec(event, bincode::config::standard()).unwrap()
+    }
+
+    async fn next_event(ws: &mut WebSocketStream<TcpStream>) -> Option<Events> {
+        loop {
+            match ws.next().await? {
+                Ok(Message::Binary(data)) => return Some(bincode::decode_from_slice(&data, bincode::config::standard()).unwrap().0),
+                Ok(Message::Close(_)) | Err(_) => return None,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_alongside_tcp() {
+        let (server, mut probe) = welcome_server();
+        let server = server.websocket(SocketAddr::from(([127, 0, 0, 1], 0))).run("127.0.0.1:0").await.unwrap();
+        let ws_addr = server.websocket_addr().unwrap();
+
+        let stream = TcpStream::connect(ws_addr).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{ws_addr}/"), stream).await.unwrap();
+        assert_eq!(next_event(&mut ws).await, Some(Events::Welcome));
+        ws.send(Message::binary(encode(&Events::Value(1)))).await.unwrap();
+        let (ws_id, event) = probe.received.recv().await.unwrap();
+        assert_eq!(event, Events::Value(1));
+
+        //both transports share the connections
+        let mut client = EventClient::<Events>::connect(server.local_addr().as_tcp().unwrap()).await.unwrap();
+        assert_round_trip(&mut client, &mut probe, 2).await;
+        assert_eq!(server.connection_ids().len(), 2);
+        server.broadcast(Events::Value(2));
+        assert_eq!(next_event(&mut ws).await, Some(Events::Value(2)));
+        assert_eq!(client.recv().await, Some(Events::Value(2)));
+
+        //oversized messages close the connection
+        ws.send(Message::binary(vec![0u8; 2048])).await.unwrap();
+        assert_eq!(next_event(&mut ws).await, None);
+        while server.connection_ids().contains(&ws_id) {
+            tokio::task::yield_now().await;
+        }
+
+        server.shutdown().await;
+    }
+}